@@ -0,0 +1,55 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// Embeds every `migrations/<version>_<name>/{up,down}.sql` in the binary, and those in
+// `migrations_sqlite/` for the `sqlite` feature, so `migrate` and `auto_migrate` don't
+// need the migrations directories or the diesel CLI where they run
+fn main() {
+    let mut generated = embed("MIGRATIONS", "migrations");
+
+    generated.push_str("\n#[cfg(feature = \"sqlite\")]\n");
+    generated.push_str(&embed("SQLITE_MIGRATIONS", "migrations_sqlite"));
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("migrations.rs"), generated).expect("Could not write embedded migrations");
+}
+
+fn embed(const_name: &str, migrations_directory: &str) -> String {
+    println!("cargo:rerun-if-changed={}", migrations_directory);
+
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let migrations_dir = Path::new(&manifest_dir).join(migrations_directory);
+
+    let mut directories: Vec<_> = fs::read_dir(&migrations_dir)
+        .expect("Could not read migrations directory")
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.join("up.sql").exists())
+        .collect();
+
+    directories.sort();
+
+    let mut generated = format!("pub const {}: &[EmbeddedMigration] = &[\n", const_name);
+
+    for directory in directories {
+        println!("cargo:rerun-if-changed={}", directory.display());
+
+        let directory_name = directory.file_name().unwrap().to_str().unwrap();
+        let (version, name) = directory_name.split_at(directory_name.find('_').unwrap_or(directory_name.len()));
+
+        // Versions match the ones the diesel CLI records, so either can apply migrations
+        let version: String = version.chars().filter(char::is_ascii_digit).collect();
+
+        generated.push_str(&format!(
+            "    EmbeddedMigration {{ version: {:?}, name: {:?}, up: include_str!({:?}), down: include_str!({:?}) }},\n",
+            version,
+            name.trim_start_matches('_'),
+            directory.join("up.sql").display().to_string(),
+            directory.join("down.sql").display().to_string()
+        ));
+    }
+
+    generated.push_str("];\n");
+    generated
+}
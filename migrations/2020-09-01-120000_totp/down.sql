@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+DROP COLUMN totp_secret,
+DROP COLUMN totp_enabled;
\ No newline at end of file
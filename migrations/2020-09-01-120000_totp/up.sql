@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD totp_secret VARCHAR,
+ADD totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
\ No newline at end of file
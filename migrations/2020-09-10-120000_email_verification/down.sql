@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE IF EXISTS user_tokens;
+
+ALTER TABLE users
+DROP COLUMN email_verified;
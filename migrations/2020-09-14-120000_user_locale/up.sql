@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD locale VARCHAR NOT NULL DEFAULT 'en';
\ No newline at end of file
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE login_codes;
+
+ALTER TABLE users
+DROP COLUMN phone;
@@ -0,0 +1,18 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD phone VARCHAR;
+
+-- One-time login codes sent by email or SMS, hashed like recovery codes since
+-- six digits are easy to brute force from a plain SHA-256
+CREATE TABLE login_codes (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    channel VARCHAR NOT NULL,
+    code_hash VARCHAR NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX login_codes_user_id_channel_idx ON login_codes (user_id, channel);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+DROP COLUMN failed_login_attempts,
+DROP COLUMN locked_until,
+DROP COLUMN is_admin;
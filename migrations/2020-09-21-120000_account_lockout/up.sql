@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD failed_login_attempts INTEGER NOT NULL DEFAULT 0,
+ADD locked_until TIMESTAMP,
+ADD is_admin BOOLEAN NOT NULL DEFAULT FALSE;
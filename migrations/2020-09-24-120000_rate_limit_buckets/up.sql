@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- Token buckets for the "postgres" rate limit store, keyed like "login:ip:203.0.113.7"
+CREATE TABLE rate_limit_buckets (
+    bucket_key VARCHAR PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+);
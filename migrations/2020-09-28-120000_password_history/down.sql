@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE password_history;
+
+ALTER TABLE users
+DROP COLUMN password_changed_at;
@@ -0,0 +1,13 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD password_changed_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+-- Hashes of passwords users had before their current one
+CREATE TABLE password_history (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    password_hash VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX password_history_user_id_idx ON password_history (user_id);
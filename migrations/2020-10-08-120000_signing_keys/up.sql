@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- JWT signing secrets, the newest unretired key signs and every unretired key verifies
+CREATE TABLE signing_keys (
+    id SERIAL PRIMARY KEY,
+    kid VARCHAR NOT NULL UNIQUE,
+    secret VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+    retired_at TIMESTAMP
+);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE users
+ADD totp_last_step BIGINT;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+-- Challenges handed out for WebAuthn ceremonies, each can only be answered once.
+-- Only a SHA-256 of the challenge is stored
+CREATE TABLE webauthn_challenges (
+    challenge_hash VARCHAR PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX webauthn_challenges_expires_at_idx ON webauthn_challenges (expires_at);
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE users
+DROP COLUMN phone_verified;
+
+ALTER TABLE users
+DROP CONSTRAINT users_phone_key;
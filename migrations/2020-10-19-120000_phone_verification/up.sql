@@ -0,0 +1,13 @@
+-- Your SQL goes here
+-- A number on several accounts can't say which one a texted code is for, so it's
+-- cleared from all of them before numbers become unique
+UPDATE users
+SET phone = NULL
+WHERE phone IN (SELECT phone FROM users GROUP BY phone HAVING COUNT(*) > 1);
+
+ALTER TABLE users
+ADD CONSTRAINT users_phone_key UNIQUE (phone);
+
+-- Login codes are only texted to numbers the user has proved are theirs
+ALTER TABLE users
+ADD phone_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+UPDATE users
+SET password = 'keyboardcat'
+WHERE email = 'alexbennettuxui@gmail.com' AND password = '$argon2id$v=19$m=4096,t=192,p=4$TdcnEEioecqb6YuEvzzyoxXkf5ucZMIZ+JkjczFeVZ0$+5lo58P4+Bh0Na9SKBI6n9HG5wzBaXTTBxhDzojPBhY';
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- The seed user was stored with a plaintext password, which can't be logged in with.
+-- This is "keyboardcat" hashed with the development `hash_secret_key`, and only
+-- replaces the plaintext so a password changed since is kept
+UPDATE users
+SET password = '$argon2id$v=19$m=4096,t=192,p=4$TdcnEEioecqb6YuEvzzyoxXkf5ucZMIZ+JkjczFeVZ0$+5lo58P4+Bh0Na9SKBI6n9HG5wzBaXTTBxhDzojPBhY'
+WHERE email = 'alexbennettuxui@gmail.com' AND password = 'keyboardcat';
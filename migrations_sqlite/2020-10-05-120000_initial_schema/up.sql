@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- The `users` table from the Postgres migrations up to 2020-09-28 in one, SQLite can't
+-- add most of these columns to an existing table. SQLite only backs users and sessions
+-- (see `SqliteRepository`), so the tables for second factors, tokens, login codes, rate
+-- limits and password history are left out
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    email TEXT NOT NULL,
+    password TEXT NOT NULL,
+    refresh_token TEXT,
+    totp_secret TEXT,
+    totp_enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE,
+    locale TEXT NOT NULL DEFAULT 'en',
+    phone TEXT,
+    failed_login_attempts INTEGER NOT NULL DEFAULT 0,
+    locked_until TIMESTAMP,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    password_changed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
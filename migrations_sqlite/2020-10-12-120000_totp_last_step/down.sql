@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`, needs SQLite 3.35 or later
+ALTER TABLE users DROP COLUMN totp_last_step;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`, needs SQLite 3.35 or later
+ALTER TABLE users DROP COLUMN phone_verified;
+
+DROP INDEX users_phone_key;
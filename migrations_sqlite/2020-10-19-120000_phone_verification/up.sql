@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- A number on several accounts can't say which one a texted code is for, so it's
+-- cleared from all of them before numbers become unique
+UPDATE users
+SET phone = NULL
+WHERE phone IN (SELECT phone FROM users GROUP BY phone HAVING COUNT(*) > 1);
+
+CREATE UNIQUE INDEX users_phone_key ON users (phone);
+
+ALTER TABLE users ADD COLUMN phone_verified BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,228 @@
+use std::io::BufRead;
+
+use std::sync::Arc;
+
+use diesel::{Connection, PgConnection};
+use futures::executor::block_on;
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::db::db_connection::{init_pool, PgPool};
+use crate::db::migrations;
+use crate::db::pg_repository::PgRepository;
+use crate::db::repository::{self, Users};
+use crate::db::storage::Backend;
+use crate::errors::errors::AppError;
+use crate::models::signing_key::SigningKey;
+use crate::models::user::{User, NewUser};
+
+const USAGE: &str = "\
+usage: auth-example [command]
+
+Without a command the server starts. Commands print JSON and exit 1 on failure.
+Passwords are read from the first line of stdin.
+
+  migrate [up|down|status]
+  create-admin <name> <email>
+  reset-password <user id>
+  unlock <user id>
+  revoke-sessions <user id>
+  rotate-signing-key
+  retire-signing-key <kid>
+  list-signing-keys";
+
+// Runs an operator command and returns its exit code, or None to start the server
+pub fn run(args: &[String]) -> Option<i32> {
+    let command = args.first()?;
+
+    // Prints text, as it did before the other commands existed
+    if command == "migrate" {
+        return Some(match migrations::run_command(&args[1..]) {
+            Ok(_) => 0,
+            Err(error) => {
+                eprintln!("{}", error);
+                1
+            }
+        });
+    }
+
+    let output = match command.as_str() {
+        "create-admin" => create_admin(&args[1..]),
+        "reset-password" => reset_password(&args[1..]),
+        "unlock" => unlock(&args[1..]),
+        "revoke-sessions" => revoke_sessions(&args[1..]),
+        "rotate-signing-key" => rotate_signing_key(),
+        "retire-signing-key" => retire_signing_key(&args[1..]),
+        "list-signing-keys" => list_signing_keys(),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            return Some(0);
+        },
+        _ => {
+            eprintln!("Unknown command `{}`\n\n{}", command, USAGE);
+            return Some(2);
+        }
+    };
+
+    match output {
+        Ok(output) => {
+            println!("{}", output);
+            Some(0)
+        },
+        Err(error) => {
+            println!("{}", error);
+            Some(1)
+        }
+    }
+}
+
+fn error(message: String) -> Value {
+    json!({ "error": message })
+}
+
+// Operators get the details that are kept from API clients
+fn app_error(app_error: AppError) -> Value {
+    match app_error {
+        AppError::Validation(errors) => json!({ "error": "Password does not meet the policy", "errors": errors }),
+        AppError::Internal(details) => error(details),
+        AppError::Database(db_error) => error(db_error.to_string()),
+        app_error => error(app_error.to_string())
+    }
+}
+
+fn postgres_url() -> Result<String, Value> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    match Backend::from_database_url(&config.database_url).map_err(error)? {
+        Backend::Postgres(database_url) => Ok(database_url),
+        Backend::Sqlite(_) => Err(error(String::from("Admin commands need a Postgres database_url")))
+    }
+}
+
+fn establish() -> Result<PgConnection, Value> {
+    PgConnection::establish(&postgres_url()?)
+        .map_err(|connection_error| error(format!("Could not connect to the database: {}", connection_error)))
+}
+
+// For commands that go through the repositories like the API does
+fn establish_pool() -> Result<PgPool, Value> {
+    init_pool(&postgres_url()?)
+        .map_err(|pool_error| error(format!("Could not connect to the database: {}", pool_error)))
+}
+
+fn argument<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str, Value> {
+    args.get(index)
+        .map(String::as_str)
+        .ok_or_else(|| error(format!("Missing <{}>", name)))
+}
+
+fn user_id_argument(args: &[String]) -> Result<i32, Value> {
+    argument(args, 0, "user id")?
+        .parse::<i32>()
+        .map_err(|_| error(String::from("<user id> must be a number")))
+}
+
+fn read_password() -> Result<String, Value> {
+    let mut password = String::new();
+
+    std::io::stdin()
+        .lock()
+        .read_line(&mut password)
+        .map_err(|read_error| error(format!("Could not read password from stdin: {}", read_error)))?;
+
+    let password = password.trim_end_matches(['\n', '\r']);
+
+    match password.is_empty() {
+        true => Err(error(String::from("Expected a password on stdin"))),
+        false => Ok(password.to_string())
+    }
+}
+
+fn create_admin(args: &[String]) -> Result<Value, Value> {
+    let name = argument(args, 0, "name")?;
+    let email = argument(args, 1, "email")?;
+    let password = read_password()?;
+    let users: Users = Arc::new(PgRepository::new(establish_pool()?));
+
+    let new_user = NewUser {
+        name: name.to_string(),
+        email: email.to_string(),
+        password,
+        locale: String::from("en"),
+        phone: None
+    };
+
+    // Signs up like `/users/create`, so the same email and password checks apply. The
+    // blocking threads and the hash pool don't need the actix runtime
+    block_on(repository::sign_up(users, new_user)).map_err(app_error)?;
+    let admin = User::grant_admin(&establish()?, email).map_err(error)?;
+
+    Ok(json!({ "user": admin, "is_admin": admin.is_admin }))
+}
+
+fn reset_password(args: &[String]) -> Result<Value, Value> {
+    let user_id = user_id_argument(args)?;
+    let password = read_password()?;
+    let conn = establish()?;
+
+    User::update_password(&conn, user_id, password).map_err(app_error)?;
+
+    // Whoever knew the old password shouldn't stay logged in
+    User::revoke_sessions(&conn, user_id)
+        .map_err(|revoke_error| error(format!("Password was reset but sessions were not revoked: {}", revoke_error)))?;
+
+    Ok(json!({ "id": user_id, "password_reset": true, "sessions_revoked": true }))
+}
+
+fn unlock(args: &[String]) -> Result<Value, Value> {
+    let user_id = user_id_argument(args)?;
+    let conn = establish()?;
+
+    User::unlock(&conn, user_id).map_err(app_error)?;
+
+    Ok(json!({ "id": user_id, "unlocked": true }))
+}
+
+fn revoke_sessions(args: &[String]) -> Result<Value, Value> {
+    let user_id = user_id_argument(args)?;
+    let conn = establish()?;
+
+    User::revoke_sessions(&conn, user_id).map_err(|_| error(String::from("User does not exist")))?;
+
+    Ok(json!({ "id": user_id, "sessions_revoked": true }))
+}
+
+fn rotate_signing_key() -> Result<Value, Value> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    let conn = establish()?;
+    let signing_key = SigningKey::rotate(&conn).map_err(error)?;
+
+    Ok(json!({
+        "signing_key": signing_key,
+        "signing_after_seconds": config.signing_key_refresh_seconds
+    }))
+}
+
+fn retire_signing_key(args: &[String]) -> Result<Value, Value> {
+    let kid = argument(args, 0, "kid")?;
+    let conn = establish()?;
+
+    let signing_key = SigningKey::retire(&conn, kid).map_err(error)?;
+
+    Ok(json!({ "signing_key": signing_key }))
+}
+
+fn list_signing_keys() -> Result<Value, Value> {
+    let conn = establish()?;
+
+    Ok(json!({ "signing_keys": SigningKey::all(&conn).map_err(error)? }))
+}
+
+#[test]
+fn starts_the_server_without_a_command() {
+    assert_eq!(run(&[]), None);
+    assert_eq!(run(&[String::from("no-such-command")]), Some(2));
+}
@@ -1,6 +1,7 @@
 use config::ConfigError;
 use serde::Deserialize;
 use dotenv::dotenv;
+use std::collections::HashMap;
 use std::env;
 
 #[derive(Deserialize, Debug)]
@@ -9,9 +10,292 @@ pub struct Config {
     pub port: i32,
     pub app_env: String,
     pub database_url: String,
+    #[serde(default = "default_db_pool_max_size")]
+    pub db_pool_max_size: u32,
+    #[serde(default)]
+    pub db_pool_min_idle: Option<u32>,
+    // How long a request waits for a free connection before getting a 503
+    #[serde(default = "default_db_connection_timeout_seconds")]
+    pub db_connection_timeout_seconds: u64,
+    #[serde(default)]
+    pub db_idle_timeout_seconds: Option<u64>,
+    // Applies pending migrations on boot, replicas take turns through an advisory lock
+    #[serde(default)]
+    pub auto_migrate: bool,
     pub hash_algo: String,
     pub hash_secret_key: String,
-    pub jwt_secret_key: String
+    // Versioned peppers, hashes record the version they were made with so the current
+    // one can change without breaking logins. Without a current version new hashes use
+    // `hash_secret_key`, as do hashes that don't record a version
+    #[serde(default)]
+    pub hash_secret_keys: HashMap<String, String>,
+    #[serde(default)]
+    pub hash_secret_key_version: Option<String>,
+    // Raising any of these upgrades each user's hash the next time they log in
+    #[serde(default = "default_argon2_variant")]
+    pub argon2_variant: String,
+    #[serde(default = "default_argon2_memory_kib")]
+    pub argon2_memory_kib: u32,
+    #[serde(default = "default_argon2_iterations")]
+    pub argon2_iterations: u32,
+    #[serde(default = "default_argon2_lanes")]
+    pub argon2_lanes: u32,
+    // Threads set aside for hashing, and how many jobs can wait for one before
+    // requests get a 503
+    #[serde(default = "default_hash_pool_threads")]
+    pub hash_pool_threads: usize,
+    #[serde(default = "default_hash_pool_queue_size")]
+    pub hash_pool_queue_size: usize,
+    // Serves `/metrics`, which has no authentication, so only turn it on where the
+    // port isn't reachable from outside
+    #[serde(default)]
+    pub metrics_enabled: bool,
+    // Signs and verifies tokens without a `kid`, i.e. until the first key rotation
+    pub jwt_secret_key: String,
+    // Turn off once a rotated key is signing, so tokens signed with `jwt_secret_key`
+    // stop working
+    #[serde(default = "default_accept_unkeyed_tokens")]
+    pub accept_unkeyed_tokens: bool,
+    // How often servers reload signing keys, also how long a new key waits before signing
+    #[serde(default = "default_signing_key_refresh_seconds")]
+    pub signing_key_refresh_seconds: u64,
+    #[serde(default = "default_totp_issuer")]
+    pub totp_issuer: String,
+    #[serde(default = "default_webauthn_rp_id")]
+    pub webauthn_rp_id: String,
+    #[serde(default = "default_totp_issuer")]
+    pub webauthn_rp_name: String,
+    #[serde(default = "default_webauthn_origin")]
+    pub webauthn_origin: String,
+    #[serde(default = "default_step_up_max_age_minutes")]
+    pub step_up_max_age_minutes: usize,
+    #[serde(default = "default_app_url")]
+    pub app_url: String,
+    #[serde(default)]
+    pub require_verified_email: bool,
+    #[serde(default = "default_email_verification_ttl_hours")]
+    pub email_verification_ttl_hours: i64,
+    #[serde(default = "default_email_verification_resend_seconds")]
+    pub email_verification_resend_seconds: i64,
+    #[serde(default = "default_password_reset_ttl_minutes")]
+    pub password_reset_ttl_minutes: i64,
+    #[serde(default = "default_password_min_length")]
+    pub password_min_length: usize,
+    #[serde(default = "default_password_min_strength")]
+    pub password_min_strength: u8,
+    // How many previous passwords, the current one included, can't be reused, 0 to allow any
+    #[serde(default)]
+    pub password_history_size: usize,
+    // Days before a password has to be changed, 0 to never expire passwords
+    #[serde(default)]
+    pub password_max_age_days: i64,
+    // SHA-1 hashes of breached passwords, one per line sorted by hash, checked when passwords are set
+    #[serde(default)]
+    pub breached_passwords_file: Option<String>,
+    // Failed password logins in a row before the account is locked, with the
+    // lockout doubling from `lockout_base_seconds` on each further failure
+    #[serde(default = "default_lockout_threshold")]
+    pub lockout_threshold: i32,
+    #[serde(default = "default_lockout_base_seconds")]
+    pub lockout_base_seconds: i64,
+    #[serde(default = "default_lockout_max_seconds")]
+    pub lockout_max_seconds: i64,
+    // "memory" keeps limits per process, "postgres" shares them between instances
+    #[serde(default = "default_rate_limit_store")]
+    pub rate_limit_store: String,
+    #[serde(default = "default_rate_limit_ip_burst")]
+    pub rate_limit_ip_burst: u32,
+    #[serde(default = "default_rate_limit_ip_per_minute")]
+    pub rate_limit_ip_per_minute: u32,
+    #[serde(default = "default_rate_limit_account_burst")]
+    pub rate_limit_account_burst: u32,
+    #[serde(default = "default_rate_limit_account_per_minute")]
+    pub rate_limit_account_per_minute: u32,
+    #[serde(default)]
+    pub magic_link_enabled: bool,
+    #[serde(default = "default_magic_link_ttl_minutes")]
+    pub magic_link_ttl_minutes: i64,
+    #[serde(default = "default_login_code_ttl_minutes")]
+    pub login_code_ttl_minutes: i64,
+    #[serde(default = "default_login_code_max_attempts")]
+    pub login_code_max_attempts: i32,
+    // "file" or "stdout" until a real SMS gateway is plugged in
+    #[serde(default = "default_sms_provider")]
+    pub sms_provider: String,
+    #[serde(default = "default_sms_file_directory")]
+    pub sms_file_directory: String,
+    // "smtp", "file" or "stdout"
+    #[serde(default = "default_mail_transport")]
+    pub mail_transport: String,
+    #[serde(default = "default_mail_from")]
+    pub mail_from: String,
+    #[serde(default = "default_mail_file_directory")]
+    pub mail_file_directory: String,
+    #[serde(default = "default_mail_max_attempts")]
+    pub mail_max_attempts: u32,
+    #[serde(default)]
+    pub smtp_host: String,
+    // 465 (implicit TLS) with `smtp_tls`, 25 without
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    #[serde(default = "default_smtp_tls")]
+    pub smtp_tls: bool,
+    #[serde(default)]
+    pub smtp_username: Option<String>,
+    #[serde(default)]
+    pub smtp_password: Option<String>
+}
+
+fn default_db_pool_max_size() -> u32 {
+    10
+}
+
+fn default_db_connection_timeout_seconds() -> u64 {
+    5
+}
+
+fn default_argon2_variant() -> String {
+    String::from("argon2id")
+}
+
+fn default_argon2_memory_kib() -> u32 {
+    4096
+}
+
+fn default_argon2_iterations() -> u32 {
+    192
+}
+
+fn default_argon2_lanes() -> u32 {
+    4
+}
+
+fn default_hash_pool_threads() -> usize {
+    2
+}
+
+fn default_hash_pool_queue_size() -> usize {
+    32
+}
+
+fn default_signing_key_refresh_seconds() -> u64 {
+    60
+}
+
+fn default_accept_unkeyed_tokens() -> bool {
+    true
+}
+
+fn default_totp_issuer() -> String {
+    String::from("actix-user-service")
+}
+
+fn default_webauthn_rp_id() -> String {
+    String::from("localhost")
+}
+
+fn default_webauthn_origin() -> String {
+    String::from("http://localhost:3000")
+}
+
+fn default_step_up_max_age_minutes() -> usize {
+    10
+}
+
+fn default_app_url() -> String {
+    String::from("http://localhost:3000")
+}
+
+fn default_email_verification_ttl_hours() -> i64 {
+    48
+}
+
+fn default_email_verification_resend_seconds() -> i64 {
+    60
+}
+
+fn default_password_reset_ttl_minutes() -> i64 {
+    60
+}
+
+fn default_password_min_length() -> usize {
+    8
+}
+
+fn default_password_min_strength() -> u8 {
+    3
+}
+
+fn default_lockout_threshold() -> i32 {
+    5
+}
+
+fn default_lockout_base_seconds() -> i64 {
+    30
+}
+
+fn default_lockout_max_seconds() -> i64 {
+    3600
+}
+
+fn default_rate_limit_store() -> String {
+    String::from("memory")
+}
+
+fn default_rate_limit_ip_burst() -> u32 {
+    20
+}
+
+fn default_rate_limit_ip_per_minute() -> u32 {
+    10
+}
+
+fn default_rate_limit_account_burst() -> u32 {
+    5
+}
+
+fn default_rate_limit_account_per_minute() -> u32 {
+    2
+}
+
+fn default_magic_link_ttl_minutes() -> i64 {
+    15
+}
+
+fn default_login_code_ttl_minutes() -> i64 {
+    10
+}
+
+fn default_login_code_max_attempts() -> i32 {
+    5
+}
+
+fn default_sms_provider() -> String {
+    String::from("stdout")
+}
+
+fn default_sms_file_directory() -> String {
+    String::from("sms")
+}
+
+fn default_mail_transport() -> String {
+    String::from("stdout")
+}
+
+fn default_mail_from() -> String {
+    String::from("actix-user-service <no-reply@localhost>")
+}
+
+fn default_mail_file_directory() -> String {
+    String::from("mail")
+}
+
+fn default_mail_max_attempts() -> u32 {
+    5
+}
+
+fn default_smtp_tls() -> bool {
+    true
 }
 
 impl Config {
@@ -1,30 +1,48 @@
-  
+use std::fmt;
+use std::time::Duration;
+
 use diesel::pg::PgConnection;
-use dotenv::dotenv;
 use diesel::r2d2::{ 
     Pool, PooledConnection, ConnectionManager, PoolError 
 };
-use actix_web::{ web, HttpResponse };
 use crate::config::Config;
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 pub type PgPooledConnection = PooledConnection<ConnectionManager<PgConnection>>;
 
-pub fn establish_connection() -> PgPool {
-    dotenv().ok();
-    let db_url = Config::from_env().expect("Please set ENV vars").database_url;
-    init_pool(&db_url).expect("Failed to create connection pool")
-}
-
 pub fn init_pool(database_url: &str) -> Result<PgPool, PoolError> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
     let manager = ConnectionManager::<PgConnection>::new(database_url);
-    Pool::builder().build(manager)
+
+    Pool::builder()
+        .max_size(config.db_pool_max_size)
+        .min_idle(config.db_pool_min_idle)
+        .connection_timeout(Duration::from_secs(config.db_connection_timeout_seconds))
+        .idle_timeout(config.db_idle_timeout_seconds.map(Duration::from_secs))
+        .build(manager)
 }
 
-pub fn pg_pool_handler(pool: web::Data<PgPool>) -> Result<PgPooledConnection, HttpResponse> {
+#[derive(Debug)]
+pub enum DbError {
+    // No connection freed up within `db_connection_timeout_seconds`
+    PoolExhausted(String),
+    // The blocking thread went away before the query finished
+    Canceled
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::PoolExhausted(error) => write!(f, "No database connection available: {}", error),
+            DbError::Canceled => write!(f, "Database query was canceled")
+        }
+    }
+}
+
+pub fn get_connection(pool: &PgPool) -> Result<PgPooledConnection, DbError> {
     pool
         .get()
-        .map_err(|e| {
-            HttpResponse::InternalServerError().json(e.to_string())
-        })
-}
\ No newline at end of file
+        .map_err(|error| DbError::PoolExhausted(error.to_string()))
+}
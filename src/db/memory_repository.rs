@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{NaiveDateTime, Utc};
+
+use crate::db::repository::{UserRepository, SessionRepository, Users, Sessions};
+use crate::errors::errors::AppError;
+use crate::models::user::{User, NewUser};
+use crate::modules::hash::hash_password;
+use crate::modules::lockout::LockoutPolicy;
+
+// Keeps users in memory so the HTTP tests run without Postgres. There are no WebAuthn
+// credentials here, so TOTP is the only second factor users can have
+#[derive(Default)]
+pub struct MemoryRepository {
+    users: Mutex<Vec<User>>
+}
+
+impl MemoryRepository {
+    pub fn new() -> MemoryRepository {
+        MemoryRepository::default()
+    }
+
+    // Skips the password policy, so fixtures can use short passwords
+    pub fn with_user(self, name: &str, email: &str, password: &str) -> MemoryRepository {
+        let password = hash_password(password.to_string())
+            .expect("Could not hash password");
+
+        self.add(name, email, password, String::from("en"), None);
+        self
+    }
+
+    pub fn into_repositories(self) -> (Users, Sessions) {
+        let repository = Arc::new(self);
+
+        (repository.clone(), repository)
+    }
+
+    fn users(&self) -> MutexGuard<'_, Vec<User>> {
+        self.users.lock().expect("Memory repository lock poisoned")
+    }
+
+    fn add(&self, name: &str, email: &str, password: String, locale: String, phone: Option<String>) -> User {
+        let mut users = self.users();
+
+        let user = User {
+            id: users.len() as i32 + 1,
+            name: name.to_string(),
+            email: email.to_string(),
+            password,
+            refresh_token: None,
+            totp_secret: None,
+            totp_enabled: false,
+            email_verified: false,
+            locale,
+            phone,
+            failed_login_attempts: 0,
+            locked_until: None,
+            is_admin: false,
+            password_changed_at: Utc::now().naive_utc(),
+            totp_last_step: None,
+            phone_verified: false
+        };
+
+        users.push(user.clone());
+        user
+    }
+
+    fn update<T>(&self, user_id: i32, change: impl FnOnce(&mut User) -> T) -> Result<T, AppError> {
+        self.users()
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .map(change)
+            .ok_or_else(AppError::user_not_found)
+    }
+}
+
+impl UserRepository for MemoryRepository {
+    fn all(&self) -> Result<Vec<User>, AppError> {
+        Ok(self.users().clone())
+    }
+
+    fn find(&self, user_id: i32) -> Result<User, AppError> {
+        self.users()
+            .iter()
+            .find(|user| user.id == user_id)
+            .cloned()
+            .ok_or_else(AppError::user_not_found)
+    }
+
+    fn find_by_name(&self, name: &str) -> Result<Option<User>, AppError> {
+        Ok(self.users().iter().find(|user| user.name == name).cloned())
+    }
+
+    fn email_taken(&self, email: &str) -> Result<bool, AppError> {
+        Ok(self.users().iter().any(|user| user.email == email))
+    }
+
+    fn phone_taken(&self, phone: &str) -> Result<bool, AppError> {
+        Ok(self.users().iter().any(|user| user.phone.as_deref() == Some(phone)))
+    }
+
+    fn insert(&self, user: NewUser) -> Result<User, AppError> {
+        Ok(self.add(&user.name, &user.email, user.password, user.locale, user.phone))
+    }
+
+    fn send_verification_email(&self, _user: &User) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn record_failed_login(&self, user_id: i32) -> Result<Option<NaiveDateTime>, AppError> {
+        self.update(user_id, |user| {
+            user.failed_login_attempts += 1;
+
+            let lockout = LockoutPolicy::from_config()
+                .lockout_for(user.failed_login_attempts)
+                .map(|lockout| Utc::now().naive_utc() + lockout);
+
+            if lockout.is_some() {
+                user.locked_until = lockout;
+            }
+
+            lockout
+        })
+    }
+
+    fn unlock(&self, user_id: i32) -> Result<(), AppError> {
+        self.update(user_id, |user| {
+            user.failed_login_attempts = 0;
+            user.locked_until = None;
+        })
+    }
+
+    fn set_password_hash(&self, user_id: i32, password_hash: String) -> Result<(), AppError> {
+        self.update(user_id, |user| user.password = password_hash)
+    }
+
+    fn mfa_methods(&self, user: &User) -> Result<Vec<String>, AppError> {
+        match user.totp_enabled {
+            true => Ok(vec![String::from("totp"), String::from("recovery_code")]),
+            false => Ok(vec![])
+        }
+    }
+}
+
+impl SessionRepository for MemoryRepository {
+    fn refresh_token(&self, user_id: i32) -> Result<Option<String>, AppError> {
+        self.find(user_id).map(|user| user.refresh_token)
+    }
+
+    fn store_refresh_token(&self, user_id: i32, refresh_token: Option<String>) -> Result<(), AppError> {
+        self.update(user_id, |user| user.refresh_token = refresh_token)
+    }
+}
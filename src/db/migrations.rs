@@ -0,0 +1,181 @@
+use diesel::{Connection, PgConnection, RunQueryDsl};
+use diesel::connection::SimpleConnection;
+use diesel::sql_types::BigInt;
+use diesel_migrations::{Migration, MigrationConnection, RunMigrationsError, run_migrations, setup_database};
+
+use crate::config::Config;
+use crate::db::storage::Backend;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedMigration {
+    pub version: &'static str,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str
+}
+
+// Generated by build.rs from `migrations/` and `migrations_sqlite/`
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
+
+impl Migration for EmbeddedMigration {
+    fn version(&self) -> &str {
+        self.version
+    }
+
+    fn run(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.up).map_err(RunMigrationsError::QueryError)
+    }
+
+    fn revert(&self, conn: &dyn SimpleConnection) -> Result<(), RunMigrationsError> {
+        conn.batch_execute(self.down).map_err(RunMigrationsError::QueryError)
+    }
+}
+
+// Any constant works as long as nothing else in the database takes the same advisory lock
+const MIGRATION_LOCK_KEY: i64 = 0x006d_6967_7261_7465;
+
+fn connection_failed(error: diesel::ConnectionError) -> String {
+    format!("Could not connect to the database: {}", error)
+}
+
+// Applies every pending migration, each in its own transaction
+pub fn run_pending<Conn: MigrationConnection>(conn: &Conn, migrations: &[EmbeddedMigration]) -> Result<(), String> {
+    run_migrations(conn, migrations.iter().cloned(), &mut std::io::stdout())
+        .map_err(|error| format!("Could not run migrations: {}", error))
+}
+
+// Replicas booting together queue up on the lock, so only the first one migrates and the
+// rest find nothing pending
+pub fn run_pending_locked(conn: &PgConnection) -> Result<(), String> {
+    diesel::sql_query("SELECT pg_advisory_lock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)
+        .map_err(|error| format!("Could not take the migration lock: {}", error))?;
+
+    let migrated = run_pending(conn, MIGRATIONS);
+
+    diesel::sql_query("SELECT pg_advisory_unlock($1)")
+        .bind::<BigInt, _>(MIGRATION_LOCK_KEY)
+        .execute(conn)
+        .map_err(|error| format!("Could not release the migration lock: {}", error))?;
+
+    migrated
+}
+
+// Reverts the most recently applied migration, returning it unless there was none
+pub fn revert_latest<Conn: MigrationConnection>(conn: &Conn, migrations: &[EmbeddedMigration]) -> Result<Option<EmbeddedMigration>, String> {
+    setup_database(conn).map_err(|error| error.to_string())?;
+
+    let latest_version = conn
+        .latest_run_migration_version()
+        .map_err(|error| error.to_string())?;
+
+    let latest_version = match latest_version {
+        Some(latest_version) => latest_version,
+        None => return Ok(None)
+    };
+
+    let migration = migrations
+        .iter()
+        .find(|migration| migration.version == latest_version)
+        .ok_or_else(|| format!("Migration {} was applied but isn't in this build", latest_version))?;
+
+    conn.transaction::<_, diesel::result::Error, _>(|| {
+        conn.batch_execute(migration.down)?;
+
+        // Versions are the digits build.rs took from directory names, and Postgres and
+        // SQLite write bind parameters differently, so it goes in the statement
+        conn.batch_execute(&format!("DELETE FROM __diesel_schema_migrations WHERE version = '{}'", migration.version))
+    })
+    .map_err(|error| format!("Could not revert migration {}: {}", migration.version, error))?;
+
+    Ok(Some(*migration))
+}
+
+// Every embedded migration and whether it has been applied
+pub fn status<Conn: MigrationConnection>(conn: &Conn, migrations: &[EmbeddedMigration]) -> Result<Vec<(EmbeddedMigration, bool)>, String> {
+    setup_database(conn).map_err(|error| error.to_string())?;
+
+    let applied = conn
+        .previously_run_migration_versions()
+        .map_err(|error| error.to_string())?;
+
+    Ok(migrations
+        .iter()
+        .map(|migration| (*migration, applied.contains(migration.version)))
+        .collect())
+}
+
+// `migrate up`, `migrate down` and `migrate status`, against whichever database
+// `database_url` points at
+pub fn run_command(args: &[String]) -> Result<(), String> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    let command = args.first().map(String::as_str).unwrap_or("up");
+
+    match Backend::from_database_url(&config.database_url)? {
+        Backend::Postgres(database_url) => {
+            let conn = PgConnection::establish(&database_url).map_err(connection_failed)?;
+
+            migrate(&conn, MIGRATIONS, command, run_pending_locked)
+        },
+        Backend::Sqlite(path) => migrate_sqlite(&path, command)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn migrate_sqlite(path: &str, command: &str) -> Result<(), String> {
+    use diesel::sqlite::SqliteConnection;
+
+    let conn = SqliteConnection::establish(path).map_err(connection_failed)?;
+
+    // SQLite takes one writer at a time on its own, so there's no lock to take
+    migrate(&conn, SQLITE_MIGRATIONS, command, |conn| run_pending(conn, SQLITE_MIGRATIONS))
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn migrate_sqlite(_path: &str, _command: &str) -> Result<(), String> {
+    Err(String::from("Build with `--features sqlite` to migrate a sqlite:// database_url"))
+}
+
+fn migrate<Conn: MigrationConnection>(conn: &Conn, migrations: &[EmbeddedMigration], command: &str, up: impl FnOnce(&Conn) -> Result<(), String>) -> Result<(), String> {
+    match command {
+        "up" => up(conn),
+        "down" => {
+            match revert_latest(conn, migrations)? {
+                Some(migration) => println!("Reverted {}_{}", migration.version, migration.name),
+                None => println!("No migrations to revert")
+            }
+
+            Ok(())
+        },
+        "status" => {
+            for (migration, applied) in status(conn, migrations)? {
+                println!("[{}] {}_{}", if applied { "X" } else { " " }, migration.version, migration.name);
+            }
+
+            Ok(())
+        },
+        command => Err(format!("Unknown migrate command `{}`, expected up, down or status", command))
+    }
+}
+
+#[test]
+fn embeds_migrations_in_order_with_diesel_versions() {
+    assert!(MIGRATIONS.len() > 1);
+    assert_eq!(MIGRATIONS[0].version, "00000000000000");
+    assert_eq!(MIGRATIONS[1].version, "20200814130946");
+    assert_eq!(MIGRATIONS[1].name, "user_table");
+    assert!(MIGRATIONS.windows(2).all(|pair| pair[0].version < pair[1].version));
+    assert!(MIGRATIONS.iter().all(|migration| !migration.up.trim().is_empty()));
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn embeds_sqlite_migrations_in_order() {
+    assert_eq!(SQLITE_MIGRATIONS[0].version, "20201005120000");
+    assert_eq!(SQLITE_MIGRATIONS[0].name, "initial_schema");
+    assert!(SQLITE_MIGRATIONS.windows(2).all(|pair| pair[0].version < pair[1].version));
+    assert!(SQLITE_MIGRATIONS.iter().all(|migration| !migration.up.trim().is_empty()));
+}
@@ -1 +1,8 @@
-pub mod db_connection;
\ No newline at end of file
+pub mod db_connection;
+pub mod migrations;
+pub mod repository;
+pub mod pg_repository;
+pub mod memory_repository;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_repository;
+pub mod storage;
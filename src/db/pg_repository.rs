@@ -0,0 +1,288 @@
+use chrono::NaiveDateTime;
+use diesel::RunQueryDsl;
+
+use crate::db::db_connection::{get_connection, PgPool, PgPooledConnection};
+use crate::db::repository::{UserRepository, SessionRepository};
+use crate::errors::errors::AppError;
+use crate::models::email_verification::{EmailVerificationManager, EmailVerification, ResendVerification};
+use crate::models::login_code::{LoginCodeManager, LoginCode, LoginCodeChannel, LoginCodeVerification, find_recipient};
+use crate::models::magic_link::{MagicLinkManager, MagicLinkRequest, MagicLinkVerification};
+use crate::models::mfa::{MfaManager, MfaVerification, TotpEnrolment, TotpConfirmation, TotpEnabled};
+use crate::models::password::{PasswordManager, ForgotPassword, PasswordReset, PasswordChange, ExpiredPasswordChange};
+use crate::models::user::{User, NewUser, Authenticated};
+use crate::models::webauthn::{
+    WebauthnManager, RegistrationOptions, AuthenticationOptions, AuthenticationOptionsRequest,
+    WebauthnRegistration, WebauthnAuthentication
+};
+
+pub struct PgRepository {
+    pool: PgPool
+}
+
+impl PgRepository {
+    pub fn new(pool: PgPool) -> PgRepository {
+        PgRepository { pool }
+    }
+
+    fn connection(&self) -> Result<PgPooledConnection, AppError> {
+        get_connection(&self.pool).map_err(AppError::from)
+    }
+}
+
+fn query_failed(error: diesel::result::Error) -> AppError {
+    AppError::Internal(error.to_string())
+}
+
+impl UserRepository for PgRepository {
+    fn all(&self) -> Result<Vec<User>, AppError> {
+        let connection = self.connection()?;
+
+        User::get_all(&connection)
+    }
+
+    fn find(&self, user_id: i32) -> Result<User, AppError> {
+        let connection = self.connection()?;
+
+        User::find(&connection, user_id)
+    }
+
+    fn find_by_name(&self, user_name: &str) -> Result<Option<User>, AppError> {
+        use crate::schema::users::dsl::{users, name};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+        use crate::diesel::OptionalExtension;
+
+        users
+            .filter(name.eq(user_name))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map_err(query_failed)
+    }
+
+    fn email_taken(&self, user_email: &str) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, email};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+        use crate::diesel::OptionalExtension;
+
+        users
+            .filter(email.eq(user_email))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map(|existing_user| existing_user.is_some())
+            .map_err(query_failed)
+    }
+
+    fn phone_taken(&self, user_phone: &str) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, phone};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+        use crate::diesel::OptionalExtension;
+
+        users
+            .filter(phone.eq(user_phone))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map(|existing_user| existing_user.is_some())
+            .map_err(query_failed)
+    }
+
+    fn insert(&self, user: NewUser) -> Result<User, AppError> {
+        use crate::schema::users::dsl::users;
+
+        diesel::insert_into(users)
+            .values(&user)
+            .get_result::<User>(&self.connection()?)
+            .map_err(|error| AppError::Internal(format!("Could not create new user: {}", error)))
+    }
+
+    fn send_verification_email(&self, user: &User) -> Result<(), String> {
+        let connection = self.connection().map_err(|error| error.to_string())?;
+
+        User::send_verification_email(&connection, user)
+    }
+
+    fn record_failed_login(&self, user_id: i32) -> Result<Option<NaiveDateTime>, AppError> {
+        let connection = self.connection()?;
+
+        User::record_failed_login(&connection, user_id).map_err(AppError::Internal)
+    }
+
+    fn unlock(&self, user_id: i32) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::unlock(&connection, user_id)
+    }
+
+    fn set_password_hash(&self, user_id: i32, password_hash: String) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::set_password_hash(&connection, user_id, password_hash).map_err(AppError::Internal)
+    }
+
+    fn mfa_methods(&self, user: &User) -> Result<Vec<String>, AppError> {
+        let connection = self.connection()?;
+
+        User::mfa_methods(&connection, user)
+    }
+
+    fn start_totp_enrolment(&self, user_id: i32) -> Result<TotpEnrolment, AppError> {
+        let connection = self.connection()?;
+
+        User::start_totp_enrolment(&connection, user_id)
+    }
+
+    fn confirm_totp_enrolment(&self, user_id: i32, confirmation: TotpConfirmation) -> Result<TotpEnabled, AppError> {
+        let connection = self.connection()?;
+
+        User::confirm_totp_enrolment(&connection, user_id, confirmation)
+    }
+
+    fn regenerate_recovery_codes(&self, user_id: i32) -> Result<Vec<String>, AppError> {
+        let connection = self.connection()?;
+
+        User::regenerate_recovery_codes(&connection, user_id)
+    }
+
+    fn verify_mfa(&self, verification: MfaVerification) -> Result<Authenticated, AppError> {
+        let connection = self.connection()?;
+
+        User::verify_mfa(&connection, verification)
+    }
+
+    fn webauthn_registration_options(&self, user_id: i32) -> Result<RegistrationOptions, AppError> {
+        let connection = self.connection()?;
+
+        User::webauthn_registration_options(&connection, user_id)
+    }
+
+    fn finish_webauthn_registration(&self, user_id: i32, registration: WebauthnRegistration) -> Result<bool, AppError> {
+        let connection = self.connection()?;
+
+        User::finish_webauthn_registration(&connection, user_id, registration)
+    }
+
+    fn webauthn_authentication_options(&self, request: AuthenticationOptionsRequest) -> Result<AuthenticationOptions, AppError> {
+        let connection = self.connection()?;
+
+        User::webauthn_authentication_options(&connection, request)
+    }
+
+    fn finish_webauthn_authentication(&self, authentication: WebauthnAuthentication) -> Result<Authenticated, AppError> {
+        let connection = self.connection()?;
+
+        User::finish_webauthn_authentication(&connection, authentication)
+    }
+
+    fn send_magic_link(&self, request: MagicLinkRequest) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::send_magic_link(&connection, request)
+    }
+
+    fn verify_magic_link(&self, verification: MagicLinkVerification) -> Result<Authenticated, AppError> {
+        let connection = self.connection()?;
+
+        User::verify_magic_link(&connection, verification)
+    }
+
+    fn find_login_code_recipient(&self, email: &Option<String>, phone: &Option<String>) -> Result<(Option<User>, LoginCodeChannel), AppError> {
+        let connection = self.connection()?;
+
+        find_recipient(&connection, email, phone)
+    }
+
+    fn send_login_code(&self, user: &User, code_channel: LoginCodeChannel, code: &str, code_hash: String) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        Ok(User::send_login_code(&connection, user, code_channel, code, code_hash)?)
+    }
+
+    fn start_login_code_verification(&self, verification: &LoginCodeVerification) -> Result<(User, LoginCodeChannel, LoginCode), AppError> {
+        let connection = self.connection()?;
+
+        User::start_login_code_verification(&connection, verification)
+    }
+
+    fn finish_login_code_verification(&self, user: User, code_channel: LoginCodeChannel, login_code: LoginCode) -> Result<Authenticated, AppError> {
+        let connection = self.connection()?;
+
+        User::finish_login_code_verification(&connection, user, code_channel, login_code)
+    }
+
+    fn send_phone_verification_code(&self, user_id: i32, code: &str, code_hash: String) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::send_phone_verification_code(&connection, user_id, code, code_hash)
+    }
+
+    fn start_phone_verification(&self, user_id: i32) -> Result<LoginCode, AppError> {
+        let connection = self.connection()?;
+
+        User::start_phone_verification(&connection, user_id)
+    }
+
+    fn finish_phone_verification(&self, user_id: i32, login_code: LoginCode) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::finish_phone_verification(&connection, user_id, login_code)
+    }
+
+    fn forgot_password(&self, request: ForgotPassword) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        Ok(User::forgot_password(&connection, request)?)
+    }
+
+    fn reset_password(&self, reset: PasswordReset) -> Result<bool, AppError> {
+        let connection = self.connection()?;
+
+        User::reset_password(&connection, reset)
+    }
+
+    fn change_password(&self, user_id: i32, change: &PasswordChange) -> Result<bool, AppError> {
+        let connection = self.connection()?;
+
+        User::change_password(&connection, user_id, change)
+    }
+
+    fn change_expired_password(&self, change: ExpiredPasswordChange) -> Result<bool, AppError> {
+        let connection = self.connection()?;
+
+        User::change_expired_password(&connection, change)
+    }
+
+    fn verify_email(&self, verification: EmailVerification) -> Result<bool, AppError> {
+        let connection = self.connection()?;
+
+        User::verify_email(&connection, verification)
+    }
+
+    fn resend_verification_email(&self, request: ResendVerification) -> Result<(), AppError> {
+        let connection = self.connection()?;
+
+        User::resend_verification_email(&connection, request)
+    }
+}
+
+impl SessionRepository for PgRepository {
+    fn refresh_token(&self, user_id: i32) -> Result<Option<String>, AppError> {
+        self.find(user_id).map(|user| user.refresh_token)
+    }
+
+    fn store_refresh_token(&self, user_id: i32, token: Option<String>) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, refresh_token};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let updated = diesel::update(users.filter(id.eq(user_id)))
+            .set(refresh_token.eq(token))
+            .execute(&self.connection()?)
+            .map_err(|error| AppError::Internal(format!("Could not store refresh token: {}", error)))?;
+
+        match updated {
+            1 => Ok(()),
+            _ => Err(AppError::user_not_found())
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use std::sync::Arc;
+
+use actix_web::{ web, error::BlockingError };
+use chrono::{NaiveDateTime, Utc};
+
+use crate::db::db_connection::DbError;
+use crate::errors::errors::AppError;
+use crate::models::email_verification::{EmailVerification, ResendVerification};
+use crate::models::login_code::{LoginCode, LoginCodeChannel, LoginCodeVerification};
+use crate::models::magic_link::{MagicLinkRequest, MagicLinkVerification};
+use crate::models::mfa::{MfaManager, MfaVerification, TotpEnrolment, TotpConfirmation, TotpEnabled};
+use crate::models::password::{PasswordManager, ForgotPassword, PasswordReset, PasswordChange, ExpiredPasswordChange};
+use crate::models::user::{
+    User, NewUser, UserLogin, UserLoggedIn, LoginOutcome, AccountLocked, NewTokens, Authenticated,
+    session_tokens, ensure_email_verified
+};
+use crate::models::webauthn::{
+    RegistrationOptions, AuthenticationOptions, AuthenticationOptionsRequest, WebauthnRegistration,
+    WebauthnAuthentication
+};
+use crate::modules::hash::{hash_password, password_matches, needs_rehash, dummy_password_check};
+use crate::modules::hash_pool;
+use crate::modules::jwt::{now, validate_token, Amr, Claims};
+use crate::modules::lockout::{LockoutPolicy, UNKNOWN_ACCOUNTS};
+use crate::modules::password_policy::validate_password;
+
+// Storage only, the rules for signing up and logging in live in the functions below so
+// every store enforces them the same way. Implementations block, so they are called
+// through `block`
+pub trait UserRepository: Send + Sync {
+    fn all(&self) -> Result<Vec<User>, AppError>;
+    fn find(&self, user_id: i32) -> Result<User, AppError>;
+    fn find_by_name(&self, name: &str) -> Result<Option<User>, AppError>;
+    fn email_taken(&self, email: &str) -> Result<bool, AppError>;
+    fn phone_taken(&self, phone: &str) -> Result<bool, AppError>;
+    // The password is already hashed
+    fn insert(&self, user: NewUser) -> Result<User, AppError>;
+    // Stores that don't verify email addresses do nothing
+    fn send_verification_email(&self, user: &User) -> Result<(), String>;
+    // Returns when the account is locked until, if this failure locked it
+    fn record_failed_login(&self, user_id: i32) -> Result<Option<NaiveDateTime>, AppError>;
+    fn unlock(&self, user_id: i32) -> Result<(), AppError>;
+    fn set_password_hash(&self, user_id: i32, password_hash: String) -> Result<(), AppError>;
+    // The second factors the user has to pass after the first one, empty for none
+    fn mfa_methods(&self, user: &User) -> Result<Vec<String>, AppError>;
+
+    // Everything past password logins. Only Postgres has the tables these need and the
+    // routes calling them are only mounted `with_postgres`, so the other stores keep the
+    // defaults. The ones that check codes or passwords run on the hash pool
+    fn start_totp_enrolment(&self, _user_id: i32) -> Result<TotpEnrolment, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn confirm_totp_enrolment(&self, _user_id: i32, _confirmation: TotpConfirmation) -> Result<TotpEnabled, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn regenerate_recovery_codes(&self, _user_id: i32) -> Result<Vec<String>, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn verify_mfa(&self, _verification: MfaVerification) -> Result<Authenticated, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn webauthn_registration_options(&self, _user_id: i32) -> Result<RegistrationOptions, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn finish_webauthn_registration(&self, _user_id: i32, _registration: WebauthnRegistration) -> Result<bool, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn webauthn_authentication_options(&self, _request: AuthenticationOptionsRequest) -> Result<AuthenticationOptions, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn finish_webauthn_authentication(&self, _authentication: WebauthnAuthentication) -> Result<Authenticated, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn send_magic_link(&self, _request: MagicLinkRequest) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn verify_magic_link(&self, _verification: MagicLinkVerification) -> Result<Authenticated, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn find_login_code_recipient(&self, _email: &Option<String>, _phone: &Option<String>) -> Result<(Option<User>, LoginCodeChannel), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn send_login_code(&self, _user: &User, _code_channel: LoginCodeChannel, _code: &str, _code_hash: String) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn start_login_code_verification(&self, _verification: &LoginCodeVerification) -> Result<(User, LoginCodeChannel, LoginCode), AppError> {
+        Err(needs_postgres())
+    }
+
+    // Once `LoginCode::matches` accepted the code
+    fn finish_login_code_verification(&self, _user: User, _code_channel: LoginCodeChannel, _login_code: LoginCode) -> Result<Authenticated, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn send_phone_verification_code(&self, _user_id: i32, _code: &str, _code_hash: String) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn start_phone_verification(&self, _user_id: i32) -> Result<LoginCode, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn finish_phone_verification(&self, _user_id: i32, _login_code: LoginCode) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn forgot_password(&self, _request: ForgotPassword) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+
+    fn reset_password(&self, _reset: PasswordReset) -> Result<bool, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn change_password(&self, _user_id: i32, _change: &PasswordChange) -> Result<bool, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn change_expired_password(&self, _change: ExpiredPasswordChange) -> Result<bool, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn verify_email(&self, _verification: EmailVerification) -> Result<bool, AppError> {
+        Err(needs_postgres())
+    }
+
+    fn resend_verification_email(&self, _request: ResendVerification) -> Result<(), AppError> {
+        Err(needs_postgres())
+    }
+}
+
+pub trait SessionRepository: Send + Sync {
+    fn refresh_token(&self, user_id: i32) -> Result<Option<String>, AppError>;
+    // `None` logs the user out
+    fn store_refresh_token(&self, user_id: i32, refresh_token: Option<String>) -> Result<(), AppError>;
+}
+
+// What handlers take as `web::Data<Users>` and `web::Data<Sessions>`
+pub type Users = Arc<dyn UserRepository>;
+pub type Sessions = Arc<dyn SessionRepository>;
+
+fn needs_postgres() -> AppError {
+    AppError::Internal(String::from("Only the Postgres store supports this"))
+}
+
+fn invalid_credentials() -> AppError {
+    AppError::Unauthorized("invalid_credentials", String::from("Invalid credentials"))
+}
+
+fn locked_or_invalid(locked_until: Option<NaiveDateTime>) -> Result<LoginOutcome, AppError> {
+    match locked_until {
+        Some(until) => Ok(LoginOutcome::Locked(AccountLocked::until(until))),
+        None => Err(invalid_credentials())
+    }
+}
+
+// Database work and email run on the blocking threads, only the password policy and
+// argon2 go to the hash pool
+pub async fn sign_up(users: Users, user: NewUser) -> Result<User, AppError> {
+    let email = user.email.clone();
+    let email_taken = block({
+        let users = users.clone();
+        move || users.email_taken(&email)
+    }).await?;
+
+    if email_taken {
+        return Err(AppError::Conflict("email_taken", String::from("Email already in use")));
+    }
+
+    if let Some(phone) = user.phone.clone() {
+        let users = users.clone();
+
+        if block(move || users.phone_taken(&phone)).await? {
+            return Err(AppError::Conflict("phone_taken", String::from("Phone number already in use")));
+        }
+    }
+
+    let user = hash_pool::run(move || -> Result<NewUser, AppError> {
+        validate_password(&user.password, &[&user.name, &user.email])?;
+
+        let password = hash_password(user.password)
+            .map_err(|error| AppError::Internal(format!("Could not hash password: {}", error)))?;
+
+        Ok(NewUser { password, ..user })
+    }).await??;
+
+    block(move || {
+        let created_user = users.insert(user)?;
+
+        // The account exists either way, the user can ask for the email to be resent
+        if let Err(error) = users.send_verification_email(&created_user) {
+            log::error!("Could not send verification email to user {}: {}", created_user.id, error);
+        }
+
+        Ok(created_user)
+    }).await
+}
+
+pub async fn login(users: Users, sessions: Sessions, login: UserLogin) -> Result<LoginOutcome, AppError> {
+    let name = login.name.clone();
+    let existing_user = block({
+        let users = users.clone();
+        move || users.find_by_name(&name)
+    }).await?;
+
+    // Unknown names and wrong passwords look the same, including when it comes to lockouts
+    let existing_user = match existing_user {
+        Some(existing_user) => existing_user,
+        None => {
+            if let Some(until) = UNKNOWN_ACCOUNTS.locked_until(&login.name) {
+                return Ok(LoginOutcome::Locked(AccountLocked::until(until)));
+            }
+
+            let password = login.password.clone();
+            hash_pool::run(move || dummy_password_check(password)).await?;
+
+            return locked_or_invalid(UNKNOWN_ACCOUNTS.record_failure(&login.name, &LockoutPolicy::from_config()));
+        }
+    };
+
+    if let Some(until) = existing_user.locked_until.filter(|until| *until > Utc::now().naive_utc()) {
+        return Ok(LoginOutcome::Locked(AccountLocked::until(until)));
+    }
+
+    let user_id = existing_user.id;
+    let password_hash = existing_user.password.clone();
+    let password = login.password.clone();
+
+    if !hash_pool::run(move || password_matches(password_hash, password)).await? {
+        let locked_until = block({
+            let users = users.clone();
+            move || users.record_failed_login(user_id)
+        }).await?;
+
+        return locked_or_invalid(locked_until);
+    }
+
+    if existing_user.failed_login_attempts > 0 {
+        let users = users.clone();
+        block(move || users.unlock(user_id)).await?;
+    }
+
+    // The only time the plaintext is available to upgrade the hash, so a failure here
+    // shouldn't stop the login
+    if needs_rehash(&existing_user.password) {
+        if let Err(error) = rehash_password(users.clone(), user_id, login.password).await {
+            log::warn!("Could not rehash password for user {}: {}", user_id, error);
+        }
+    }
+
+    if User::password_has_expired(&existing_user) {
+        return Ok(LoginOutcome::PasswordExpired(User::password_change_token(&existing_user)));
+    }
+
+    block(move || complete_first_factor(&*users, &*sessions, existing_user, Amr::Pwd)).await
+}
+
+// Issues tokens for any login that got past its own checks, or asks for a second factor
+pub async fn finish_login(users: Users, sessions: Sessions, authenticated: Authenticated) -> Result<LoginOutcome, AppError> {
+    block(move || match authenticated {
+        Authenticated::FirstFactor(existing_user, first_factor) => complete_first_factor(&*users, &*sessions, existing_user, first_factor),
+        Authenticated::AllFactors(existing_user, amr) => issue_tokens(&*sessions, existing_user, amr).map(LoginOutcome::LoggedIn),
+        Authenticated::Stopped(login_outcome) => Ok(login_outcome)
+    }).await
+}
+
+async fn rehash_password(users: Users, user_id: i32, password: String) -> Result<(), AppError> {
+    let password_hash = hash_pool::run(move || hash_password(password))
+        .await?
+        .map_err(|error| AppError::Internal(format!("Could not hash password: {}", error)))?;
+
+    block(move || users.set_password_hash(user_id, password_hash)).await
+}
+
+fn complete_first_factor(users: &dyn UserRepository, sessions: &dyn SessionRepository, existing_user: User, first_factor: Amr) -> Result<LoginOutcome, AppError> {
+    let mfa_methods = users.mfa_methods(&existing_user)?;
+
+    if !mfa_methods.is_empty() {
+        return Ok(LoginOutcome::MfaRequired(User::mfa_challenge(&existing_user, first_factor, mfa_methods)));
+    }
+
+    issue_tokens(sessions, existing_user, vec![first_factor]).map(LoginOutcome::LoggedIn)
+}
+
+fn issue_tokens(sessions: &dyn SessionRepository, existing_user: User, amr: Vec<Amr>) -> Result<UserLoggedIn, AppError> {
+    ensure_email_verified(&existing_user)?;
+
+    let NewTokens { refresh_token, access_token } = session_tokens(existing_user.id, amr, now());
+    sessions.store_refresh_token(existing_user.id, Some(refresh_token.clone()))?;
+
+    Ok(UserLoggedIn {
+        name: existing_user.name,
+        email: existing_user.email,
+        jwt: access_token,
+        refresh_token: Some(refresh_token)
+    })
+}
+
+pub async fn logout(sessions: Sessions, user_id: i32) -> Result<(), AppError> {
+    block(move || sessions.store_refresh_token(user_id, None)).await
+}
+
+// Swaps the user's current refresh token for new tokens, keeping the original login's claims
+pub async fn refresh(sessions: Sessions, user_id: i32, refresh_token: String, refresh_claims: Claims) -> Result<NewTokens, AppError> {
+    block(move || {
+        let stored_refresh_token = sessions.refresh_token(user_id)?;
+
+        // Logging out clears the stored token, so it can't match any more
+        if stored_refresh_token != Some(refresh_token.clone()) || !validate_token(&refresh_token) {
+            return Err(AppError::Unauthorized("refresh_token_mismatch", String::from("Failed to reauth: refresh tokens do not match.")));
+        }
+
+        replace_tokens(&*sessions, user_id, refresh_claims)
+    }).await
+}
+
+// Gives the caller new tokens for the login they are already in. Only one refresh token
+// is kept per user, so this also logs every other session out
+pub async fn revoke_other_sessions(sessions: Sessions, user_id: i32, claims: Claims) -> Result<NewTokens, AppError> {
+    block(move || replace_tokens(&*sessions, user_id, claims)).await
+}
+
+fn replace_tokens(sessions: &dyn SessionRepository, user_id: i32, claims: Claims) -> Result<NewTokens, AppError> {
+    let new_tokens = session_tokens(user_id, claims.amr, claims.auth_time);
+    sessions.store_refresh_token(user_id, Some(new_tokens.refresh_token.clone()))?;
+
+    Ok(new_tokens)
+}
+
+pub async fn block<F, T>(call: F) -> Result<T, AppError>
+where
+    F: FnOnce() -> Result<T, AppError> + Send + 'static,
+    T: Send + 'static
+{
+    web::block(call)
+        .await
+        .map_err(|error| match error {
+            BlockingError::Error(error) => error,
+            BlockingError::Canceled => AppError::Database(DbError::Canceled)
+        })
+}
@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
+use diesel::sqlite::SqliteConnection;
+use diesel::r2d2::{ self, Pool, PooledConnection, ConnectionManager, CustomizeConnection, PoolError };
+
+use crate::config::Config;
+use crate::db::db_connection::DbError;
+use crate::db::repository::{UserRepository, SessionRepository};
+use crate::errors::errors::AppError;
+use crate::models::user::{User, NewUser};
+use crate::modules::lockout::LockoutPolicy;
+
+pub type SqlitePool = Pool<ConnectionManager<SqliteConnection>>;
+pub type SqlitePooledConnection = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+// `database_url` is the path to the database file, e.g. "sqlite://user_store.db"
+pub fn init_sqlite_pool(path: &str) -> Result<SqlitePool, PoolError> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    let manager = ConnectionManager::<SqliteConnection>::new(path);
+
+    Pool::builder()
+        .max_size(config.db_pool_max_size)
+        .connection_timeout(Duration::from_secs(config.db_connection_timeout_seconds))
+        .connection_customizer(Box::new(SqlitePragmas))
+        .build(manager)
+}
+
+// WAL lets reads carry on during a write, and writers queue behind each other for up
+// to `busy_timeout` milliseconds instead of failing with "database is locked"
+#[derive(Debug)]
+struct SqlitePragmas;
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for SqlitePragmas {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> Result<(), r2d2::Error> {
+        connection
+            .batch_execute("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000; PRAGMA foreign_keys = ON;")
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+// Users and sessions against a SQLite file, sharing the `users` table and models with
+// Postgres. That covers signing up, password logins with lockouts, sessions and the admin
+// endpoints. Second factors, passwordless logins, email verification and password
+// changes keep the `UserRepository` defaults, their routes aren't mounted and
+// `Storage::sqlite` refuses the settings that depend on them
+pub struct SqliteRepository {
+    pool: SqlitePool
+}
+
+impl SqliteRepository {
+    pub fn new(pool: SqlitePool) -> SqliteRepository {
+        SqliteRepository { pool }
+    }
+
+    fn connection(&self) -> Result<SqlitePooledConnection, AppError> {
+        self.pool
+            .get()
+            .map_err(|error| AppError::Database(DbError::PoolExhausted(error.to_string())))
+    }
+}
+
+fn query_failed(error: diesel::result::Error) -> AppError {
+    AppError::Internal(error.to_string())
+}
+
+impl UserRepository for SqliteRepository {
+    fn all(&self) -> Result<Vec<User>, AppError> {
+        use crate::schema::users::dsl::users;
+
+        users
+            .load::<User>(&self.connection()?)
+            .map_err(query_failed)
+    }
+
+    fn find(&self, user_id: i32) -> Result<User, AppError> {
+        use crate::schema::users::dsl::{users, id};
+
+        users
+            .filter(id.eq(user_id))
+            .first::<User>(&self.connection()?)
+            .map_err(|_| AppError::user_not_found())
+    }
+
+    fn find_by_name(&self, user_name: &str) -> Result<Option<User>, AppError> {
+        use crate::schema::users::dsl::{users, name};
+
+        users
+            .filter(name.eq(user_name))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map_err(query_failed)
+    }
+
+    fn email_taken(&self, user_email: &str) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, email};
+
+        users
+            .filter(email.eq(user_email))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map(|existing_user| existing_user.is_some())
+            .map_err(query_failed)
+    }
+
+    fn phone_taken(&self, user_phone: &str) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, phone};
+
+        users
+            .filter(phone.eq(user_phone))
+            .first::<User>(&self.connection()?)
+            .optional()
+            .map(|existing_user| existing_user.is_some())
+            .map_err(query_failed)
+    }
+
+    fn insert(&self, user: NewUser) -> Result<User, AppError> {
+        use crate::schema::users::dsl::{users, email};
+
+        let connection = self.connection()?;
+
+        // No RETURNING here, so the new row is read back by its unique email
+        diesel::insert_into(users)
+            .values(&user)
+            .execute(&connection)
+            .map_err(|error| AppError::Internal(format!("Could not create new user: {}", error)))?;
+
+        users
+            .filter(email.eq(&user.email))
+            .first::<User>(&connection)
+            .map_err(query_failed)
+    }
+
+    fn send_verification_email(&self, _user: &User) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn record_failed_login(&self, user_id: i32) -> Result<Option<NaiveDateTime>, AppError> {
+        use crate::schema::users::dsl::{users, id, failed_login_attempts, locked_until};
+
+        let connection = self.connection()?;
+
+        // Incremented in the database so concurrent guesses all count, and locked in the
+        // same transaction so a failure can't leave the count past the limit but unlocked
+        connection.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::update(users.filter(id.eq(user_id)))
+                .set(failed_login_attempts.eq(failed_login_attempts + 1))
+                .execute(&connection)?;
+
+            let updated_user = users.filter(id.eq(user_id)).first::<User>(&connection)?;
+
+            let lockout = LockoutPolicy::from_config()
+                .lockout_for(updated_user.failed_login_attempts)
+                .map(|lockout| Utc::now().naive_utc() + lockout);
+
+            if lockout.is_some() {
+                diesel::update(users.filter(id.eq(user_id)))
+                    .set(locked_until.eq(lockout))
+                    .execute(&connection)?;
+            }
+
+            Ok(lockout)
+        })
+        .map_err(|error| AppError::Internal(format!("Could not record failed login: {}", error)))
+    }
+
+    fn unlock(&self, user_id: i32) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, failed_login_attempts, locked_until};
+
+        let unlocked = diesel::update(users.filter(id.eq(user_id)))
+            .set((failed_login_attempts.eq(0), locked_until.eq(None::<NaiveDateTime>)))
+            .execute(&self.connection()?)
+            .map_err(|error| AppError::Internal(format!("Could not unlock account: {}", error)))?;
+
+        match unlocked {
+            1 => Ok(()),
+            _ => Err(AppError::user_not_found())
+        }
+    }
+
+    fn set_password_hash(&self, user_id: i32, password_hash: String) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, password};
+
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(password.eq(password_hash))
+            .execute(&self.connection()?)
+            .map(|_updated| ())
+            .map_err(|error| AppError::Internal(format!("Could not rehash password: {}", error)))
+    }
+
+    // Second factors can't be set up or checked here, so a user who has one anyway, e.g.
+    // from an imported database, can't log in rather than logging in without it
+    fn mfa_methods(&self, user: &User) -> Result<Vec<String>, AppError> {
+        match user.totp_enabled {
+            true => Err(AppError::Internal(format!("User {} has TOTP enabled, which SQLite doesn't support", user.id))),
+            false => Ok(vec![])
+        }
+    }
+}
+
+impl SessionRepository for SqliteRepository {
+    fn refresh_token(&self, user_id: i32) -> Result<Option<String>, AppError> {
+        self.find(user_id).map(|user| user.refresh_token)
+    }
+
+    fn store_refresh_token(&self, user_id: i32, token: Option<String>) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, refresh_token};
+
+        let updated = diesel::update(users.filter(id.eq(user_id)))
+            .set(refresh_token.eq(token))
+            .execute(&self.connection()?)
+            .map_err(|error| AppError::Internal(format!("Could not store refresh token: {}", error)))?;
+
+        match updated {
+            1 => Ok(()),
+            _ => Err(AppError::user_not_found())
+        }
+    }
+}
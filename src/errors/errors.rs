@@ -1,9 +1,290 @@
+use std::fmt;
+
 use actix_web::middleware::errhandlers::{ ErrorHandlerResponse};
-use actix_web::{dev, http, Error};
+use actix_web::{dev, http, web, Error, HttpResponse, ResponseError, http::{header, StatusCode}};
+use actix_web::dev::{Body, HttpResponseBuilder, ResponseBody};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::db::db_connection::DbError;
+use crate::models::user::AccountLocked;
+use crate::modules::hash_pool::HashPoolError;
+use crate::modules::password_policy::FieldError;
+
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+// An RFC 7807 problem details body. `code` is stable for clients to branch on, `detail`
+// is for people and may change
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    // Members only some problems have, e.g. `retry_after`
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: &str, detail: String) -> Problem {
+        Problem {
+            problem_type: format!("/problems/{}", code),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            status: status.as_u16(),
+            detail,
+            code: code.to_string(),
+            errors: Vec::new(),
+            extensions: Map::new()
+        }
+    }
+
+    pub fn with_errors(self, errors: Vec<FieldError>) -> Problem {
+        Problem { errors, ..self }
+    }
+
+    pub fn with_extension<T: Serialize>(mut self, name: &str, value: T) -> Problem {
+        self.extensions.insert(name.to_string(), serde_json::to_value(value).unwrap_or(Value::Null));
+        self
+    }
+
+    // Takes a builder so callers can add headers, e.g. Retry-After
+    pub fn respond(self, response: &mut HttpResponseBuilder) -> HttpResponse {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+
+        response
+            .status(status)
+            .content_type(PROBLEM_JSON)
+            .json(self)
+    }
+}
+
+// Handler errors already come as problems, anything else that ends in a 500 (a panic,
+// an error from a library) is replaced with a generic one
+pub fn render_500<B>(res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, Error> {
+    let is_problem = res
+        .headers()
+        .get(http::header::CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == PROBLEM_JSON);
+
+    if is_problem {
+        return Ok(ErrorHandlerResponse::Response(res));
+    }
+
+    let problem = Problem::new(StatusCode::INTERNAL_SERVER_ERROR, "internal_error", String::from("Something went wrong"));
+    let body = serde_json::to_string(&problem)?;
+
+    let mut res = res.map_body(|_head, _body| ResponseBody::Other(Body::from(body)));
+    res.headers_mut()
+       .insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static(PROBLEM_JSON));
 
-pub fn render_500<B>(mut res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>, Error> {
-    res.response_mut()
-       .headers_mut()
-       .insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("Error"));
     Ok(ErrorHandlerResponse::Response(res))
-}
\ No newline at end of file
+}
+
+// Bodies that aren't JSON or don't match the handler's type get a problem too, rather
+// than actix's plain text 400
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default()
+        .error_handler(|error, _req| AppError::BadRequest("invalid_body", error.to_string()).into())
+}
+
+// Every way a request can fail, so handlers can return `Result<HttpResponse, AppError>`
+// and let the variant pick the status code. The `&'static str` is the problem's `code`
+#[derive(Debug)]
+pub enum AppError {
+    // 400
+    BadRequest(&'static str, String),
+    // 400, with what was wrong with each field
+    Validation(Vec<FieldError>),
+    // 401, missing or wrong credentials or tokens
+    Unauthorized(&'static str, String),
+    // 403
+    Forbidden(&'static str, String),
+    // 404
+    NotFound(&'static str, String),
+    // 409, e.g. an email that's already registered
+    Conflict(&'static str, String),
+    // 429 until the lockout runs out
+    Locked(AccountLocked),
+    // 500, the details are logged rather than sent to the client
+    Internal(String),
+    // 503, no connection was free in time. Like `Internal`, the details are only logged
+    Database(DbError),
+    // 503 when saturated, 500 when a job panicked
+    HashPool(HashPoolError)
+}
+
+impl AppError {
+    pub fn invalid_token() -> AppError {
+        AppError::Unauthorized("invalid_token", String::from("Token is invalid or expired"))
+    }
+
+    pub fn user_not_found() -> AppError {
+        AppError::NotFound("user_not_found", String::from("User does not exist"))
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(code, _)
+            | AppError::Unauthorized(code, _)
+            | AppError::Forbidden(code, _)
+            | AppError::NotFound(code, _)
+            | AppError::Conflict(code, _) => code,
+            AppError::Validation(_) => "validation_failed",
+            AppError::Locked(_) => "account_locked",
+            AppError::Internal(_) => "internal_error",
+            AppError::Database(_) => "database_unavailable",
+            AppError::HashPool(HashPoolError::Saturated) => "server_busy",
+            AppError::HashPool(HashPoolError::Canceled) => "hashing_failed"
+        }
+    }
+
+    // Seconds before trying again is worth it
+    pub fn retry_after(&self) -> Option<i64> {
+        match self {
+            AppError::Locked(AccountLocked { retry_after }) => Some(*retry_after),
+            AppError::Database(_) | AppError::HashPool(HashPoolError::Saturated) => Some(1),
+            _ => None
+        }
+    }
+
+    pub fn problem(&self) -> Problem {
+        let problem = Problem::new(self.status_code(), self.code(), self.to_string());
+
+        match self {
+            AppError::Validation(field_errors) => problem.with_errors(field_errors.clone()),
+            AppError::Locked(AccountLocked { retry_after }) => problem.with_extension("retry_after", retry_after),
+            _ => problem
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppError::BadRequest(_, detail)
+            | AppError::Unauthorized(_, detail)
+            | AppError::Forbidden(_, detail)
+            | AppError::NotFound(_, detail)
+            | AppError::Conflict(_, detail) => write!(f, "{}", detail),
+            AppError::Validation(_) => write!(f, "Some fields are invalid"),
+            AppError::Locked(_) => write!(f, "Too many failed logins, try again later"),
+            AppError::Internal(_) => write!(f, "Something went wrong"),
+            AppError::Database(_) => write!(f, "The database is unavailable, try again later"),
+            AppError::HashPool(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl ResponseError for AppError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(..) | AppError::Validation(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(..) => StatusCode::FORBIDDEN,
+            AppError::NotFound(..) => StatusCode::NOT_FOUND,
+            AppError::Conflict(..) => StatusCode::CONFLICT,
+            AppError::Locked(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Internal(_) | AppError::HashPool(HashPoolError::Canceled) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Database(_) | AppError::HashPool(HashPoolError::Saturated) => StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AppError::Internal(details) => log::error!("Internal error: {}", details),
+            AppError::Database(error) => log::error!("Database error: {}", error),
+            _ => ()
+        }
+
+        let mut response = HttpResponse::build(self.status_code());
+
+        if let Some(retry_after) = self.retry_after() {
+            response.header(header::RETRY_AFTER, retry_after.to_string());
+        }
+
+        self.problem().respond(&mut response)
+    }
+}
+
+// Model helpers report their own failures (a query, sending mail) as strings. Those
+// are ours rather than the client's, so they become a 500
+impl From<String> for AppError {
+    fn from(details: String) -> AppError {
+        AppError::Internal(details)
+    }
+}
+
+// So model helpers can share a transaction, which has to be able to report its own
+// BEGIN and COMMIT failing
+impl From<diesel::result::Error> for AppError {
+    fn from(error: diesel::result::Error) -> AppError {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<DbError> for AppError {
+    fn from(error: DbError) -> AppError {
+        AppError::Database(error)
+    }
+}
+
+impl From<HashPoolError> for AppError {
+    fn from(error: HashPoolError) -> AppError {
+        AppError::HashPool(error)
+    }
+}
+
+impl From<Vec<FieldError>> for AppError {
+    fn from(field_errors: Vec<FieldError>) -> AppError {
+        AppError::Validation(field_errors)
+    }
+}
+
+#[test]
+fn maps_errors_to_status_codes() {
+    assert_eq!(AppError::BadRequest("bad_request", String::from("Bad")).status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(AppError::Validation(Vec::new()).status_code(), StatusCode::BAD_REQUEST);
+    assert_eq!(AppError::Unauthorized("invalid_credentials", String::from("Invalid credentials")).status_code(), StatusCode::UNAUTHORIZED);
+    assert_eq!(AppError::Forbidden("admin_required", String::from("No")).status_code(), StatusCode::FORBIDDEN);
+    assert_eq!(AppError::user_not_found().status_code(), StatusCode::NOT_FOUND);
+    assert_eq!(AppError::Conflict("email_taken", String::from("Email already in use")).status_code(), StatusCode::CONFLICT);
+    assert_eq!(AppError::Locked(AccountLocked { retry_after: 30 }).status_code(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(AppError::Internal(String::from("Boom")).status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(AppError::from(HashPoolError::Saturated).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(AppError::from(DbError::PoolExhausted(String::from("timed out"))).status_code(), StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[test]
+fn keeps_internal_details_out_of_responses() {
+    let error = AppError::Internal(String::from("relation \"users\" does not exist"));
+
+    assert_eq!(error.to_string(), "Something went wrong");
+    assert_eq!(error.problem().detail, "Something went wrong");
+
+    let error = AppError::from(DbError::PoolExhausted(String::from("timed out waiting for connection to 10.0.0.5")));
+
+    assert!(!error.problem().detail.contains("10.0.0.5"));
+}
+
+#[test]
+fn describes_errors_as_problems() {
+    let problem = AppError::Locked(AccountLocked { retry_after: 30 }).problem();
+
+    assert_eq!(problem.problem_type, "/problems/account_locked");
+    assert_eq!(problem.title, "Too Many Requests");
+    assert_eq!(problem.status, 429);
+    assert_eq!(problem.code, "account_locked");
+    assert_eq!(problem.extensions.get("retry_after"), Some(&Value::from(30)));
+
+    let field_error = FieldError::password("too_short", String::from("Password must be at least 8 characters"));
+    let problem = serde_json::to_value(AppError::Validation(vec![field_error]).problem()).unwrap();
+
+    assert_eq!(problem["type"], "/problems/validation_failed");
+    assert_eq!(problem["errors"][0]["code"], "too_short");
+    assert!(problem.get("retry_after").is_none());
+}
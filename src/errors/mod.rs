@@ -1 +1,2 @@
-pub mod errors;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod errors;
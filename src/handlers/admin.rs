@@ -0,0 +1,32 @@
+use crate::db::repository::{self, Users};
+use crate::errors::errors::AppError;
+use crate::middleware::auth::authenticated_user_id;
+use actix_web::{ web, HttpResponse, HttpRequest };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UnlockResponse {
+    pub unlocked: bool
+}
+
+pub async fn unlock_user(req: HttpRequest, users: web::Data<Users>, id: web::Path<i32>) -> Result<HttpResponse, AppError> {
+    let admin_id = authenticated_user_id(&req).expect("Auth middleware must run before admin handlers");
+    let user_id = id.into_inner();
+    let users = users.get_ref().clone();
+
+    repository::block(move || {
+        // A token can outlive its user, who is no admin any more
+        let is_admin = match users.find(admin_id) {
+            Ok(admin) => admin.is_admin,
+            Err(AppError::NotFound(..)) => false,
+            Err(error) => return Err(error)
+        };
+
+        if !is_admin {
+            return Err(AppError::Forbidden("admin_required", String::from("Admin access required")));
+        }
+
+        users.unlock(user_id)
+    }).await?;
+
+    Ok(HttpResponse::Ok().json(UnlockResponse { unlocked: true }))
+}
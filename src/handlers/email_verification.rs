@@ -0,0 +1,32 @@
+use crate::models::email_verification::{EmailVerification, ResendVerification};
+use crate::db::repository::{self, Users};
+use crate::errors::errors::AppError;
+use actix_web::{ web, HttpResponse };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmailVerifiedResponse {
+    pub email_verified: bool
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VerificationSentResponse {
+    pub verification_email_sent: bool
+}
+
+pub async fn verify_email(users: web::Data<Users>, verification: web::Json<EmailVerification>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let verification = verification.into_inner();
+
+    let email_verified = repository::block(move || users.verify_email(verification)).await?;
+
+    Ok(HttpResponse::Ok().json(EmailVerifiedResponse { email_verified }))
+}
+
+pub async fn resend_verification_email(users: web::Data<Users>, request: web::Json<ResendVerification>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let request = request.into_inner();
+
+    repository::block(move || users.resend_verification_email(request)).await?;
+
+    Ok(HttpResponse::Ok().json(VerificationSentResponse { verification_email_sent: true }))
+}
@@ -1,4 +1,5 @@
 use actix_web::{ Responder, get, HttpResponse };
+use crate::modules::hash_pool::{HASH_POOL, HashPoolStats};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthResponse {
@@ -10,4 +11,16 @@ pub async fn status() -> impl Responder {
     HttpResponse::Ok().json(HealthResponse {
         status: String::from("pong!")
     })
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsResponse {
+    pub hash_pool: HashPoolStats
+}
+
+#[get("/metrics")]
+pub async fn metrics() -> impl Responder {
+    HttpResponse::Ok().json(MetricsResponse {
+        hash_pool: HASH_POOL.stats()
+    })
+}
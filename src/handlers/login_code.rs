@@ -0,0 +1,121 @@
+use crate::models::login_code::{LoginCode, LoginCodeRequest, LoginCodeVerification, PhoneVerification, incorrect_code, recipient_required};
+use crate::db::repository::{self, Users, Sessions};
+use crate::handlers::user::login_outcome_response;
+use crate::errors::errors::AppError;
+use crate::middleware::auth::authenticated_user_id;
+use crate::modules::hash_pool;
+use actix_web::{ web, HttpResponse, HttpRequest };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LoginCodeSentResponse {
+    pub message: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PhoneVerifiedResponse {
+    pub phone_verified: bool
+}
+
+// Unknown recipients and delivery problems are swallowed so this can't be used to
+// find accounts. For the same reason the lookup, hashing and sending happen after
+// responding, or a real account would take longer to answer
+pub async fn send_login_code(users: web::Data<Users>, request: web::Json<LoginCodeRequest>) -> Result<HttpResponse, AppError> {
+    let request = request.into_inner();
+
+    if request.email.is_none() && request.phone.is_none() {
+        return Err(recipient_required());
+    }
+
+    let users = users.get_ref().clone();
+
+    actix_rt::spawn(async move {
+        if let Err(error) = issue_login_code(users, request).await {
+            log::error!("Could not send login code: {}", error);
+        }
+    });
+
+    Ok(HttpResponse::Ok().json(LoginCodeSentResponse {
+        message: String::from("If an account exists for that email or phone number, a login code has been sent")
+    }))
+}
+
+// Only hashing the code goes to the hash pool, storing and sending it don't
+async fn issue_login_code(users: Users, request: LoginCodeRequest) -> Result<(), AppError> {
+    let recipient = repository::block({
+        let users = users.clone();
+        move || users.find_login_code_recipient(&request.email, &request.phone)
+    }).await?;
+
+    let (existing_user, code_channel) = match recipient {
+        (Some(existing_user), code_channel) => (existing_user, code_channel),
+        (None, _) => return Ok(())
+    };
+
+    let (code, code_hash) = hash_pool::run(LoginCode::generate).await??;
+
+    repository::block(move || users.send_login_code(&existing_user, code_channel, &code, code_hash)).await
+}
+
+pub async fn verify_login_code(users: web::Data<Users>, sessions: web::Data<Sessions>, verification: web::Json<LoginCodeVerification>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let verification = verification.into_inner();
+    let code = verification.code.clone();
+
+    let (existing_user, code_channel, login_code) = repository::block({
+        let users = users.clone();
+        move || users.start_login_code_verification(&verification)
+    }).await?;
+
+    let (login_code, code_matches) = hash_pool::run(move || {
+        let code_matches = login_code.matches(&code);
+        (login_code, code_matches)
+    }).await?;
+
+    if !code_matches {
+        return Err(incorrect_code());
+    }
+
+    let authenticated = repository::block({
+        let users = users.clone();
+        move || users.finish_login_code_verification(existing_user, code_channel, login_code)
+    }).await?;
+
+    login_outcome_response(repository::finish_login(users, sessions.get_ref().clone(), authenticated).await)
+}
+
+pub async fn send_phone_verification_code(req: HttpRequest, users: web::Data<Users>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before verifying a phone number");
+    let users = users.get_ref().clone();
+
+    let (code, code_hash) = hash_pool::run(LoginCode::generate).await??;
+
+    repository::block(move || users.send_phone_verification_code(user_id, &code, code_hash)).await?;
+
+    Ok(HttpResponse::Ok().json(LoginCodeSentResponse {
+        message: String::from("A verification code has been sent")
+    }))
+}
+
+pub async fn verify_phone(req: HttpRequest, users: web::Data<Users>, verification: web::Json<PhoneVerification>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before verifying a phone number");
+    let users = users.get_ref().clone();
+    let code = verification.into_inner().code;
+
+    let login_code = repository::block({
+        let users = users.clone();
+        move || users.start_phone_verification(user_id)
+    }).await?;
+
+    let (login_code, code_matches) = hash_pool::run(move || {
+        let code_matches = login_code.matches(&code);
+        (login_code, code_matches)
+    }).await?;
+
+    if !code_matches {
+        return Err(incorrect_code());
+    }
+
+    repository::block(move || users.finish_phone_verification(user_id, login_code)).await?;
+
+    Ok(HttpResponse::Ok().json(PhoneVerifiedResponse { phone_verified: true }))
+}
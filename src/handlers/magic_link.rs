@@ -0,0 +1,33 @@
+use crate::models::magic_link::{MagicLinkRequest, MagicLinkVerification};
+use crate::db::repository::{self, Users, Sessions};
+use crate::handlers::user::login_outcome_response;
+use crate::errors::errors::AppError;
+use actix_web::{ web, HttpResponse };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicLinkSentResponse {
+    pub message: String
+}
+
+pub async fn send_magic_link(users: web::Data<Users>, request: web::Json<MagicLinkRequest>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let request = request.into_inner();
+
+    repository::block(move || users.send_magic_link(request)).await?;
+
+    Ok(HttpResponse::Ok().json(MagicLinkSentResponse {
+        message: String::from("If an account exists for that email, a login link has been sent")
+    }))
+}
+
+pub async fn verify_magic_link(users: web::Data<Users>, sessions: web::Data<Sessions>, verification: web::Json<MagicLinkVerification>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let verification = verification.into_inner();
+
+    let authenticated = repository::block({
+        let users = users.clone();
+        move || users.verify_magic_link(verification)
+    }).await?;
+
+    login_outcome_response(repository::finish_login(users, sessions.get_ref().clone(), authenticated).await)
+}
@@ -0,0 +1,46 @@
+use crate::models::mfa::{TotpEnrolment, TotpConfirmation};
+use crate::db::repository::{self, Users};
+use crate::errors::errors::AppError;
+use crate::modules::hash_pool;
+use crate::middleware::auth::authenticated_user_id;
+use actix_web::{ web, HttpResponse, HttpRequest };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrolmentResponse {
+    pub totp_enrolment: TotpEnrolment
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>
+}
+
+pub async fn start_totp_enrolment(req: HttpRequest, users: web::Data<Users>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before TOTP enrolment");
+    let users = users.get_ref().clone();
+
+    let totp_enrolment = repository::block(move || users.start_totp_enrolment(user_id)).await?;
+
+    Ok(HttpResponse::Ok().json(TotpEnrolmentResponse { totp_enrolment }))
+}
+
+pub async fn confirm_totp_enrolment(req: HttpRequest, users: web::Data<Users>, confirmation: web::Json<TotpConfirmation>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before TOTP confirmation");
+    let users = users.get_ref().clone();
+    let confirmation = confirmation.into_inner();
+
+    // Enabling TOTP hashes a fresh set of recovery codes
+    let totp_enabled = hash_pool::run(move || users.confirm_totp_enrolment(user_id, confirmation)).await??;
+
+    Ok(HttpResponse::Ok().json(totp_enabled))
+}
+
+pub async fn regenerate_recovery_codes(req: HttpRequest, users: web::Data<Users>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before regenerating recovery codes");
+    let users = users.get_ref().clone();
+
+    // Each code is an argon2 hash
+    let recovery_codes = hash_pool::run(move || users.regenerate_recovery_codes(user_id)).await??;
+
+    Ok(HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes }))
+}
@@ -1,2 +1,9 @@
 pub mod user;
-pub mod health;
\ No newline at end of file
+pub mod health;
+pub mod mfa;
+pub mod webauthn;
+pub mod email_verification;
+pub mod password;
+pub mod magic_link;
+pub mod login_code;
+pub mod admin;
\ No newline at end of file
@@ -0,0 +1,88 @@
+use crate::models::user::NewTokens;
+use crate::models::password::{ForgotPassword, PasswordReset, PasswordChange, ExpiredPasswordChange};
+use crate::db::repository::{self, Users, Sessions};
+use crate::errors::errors::AppError;
+use crate::handlers::user::refresh_token_cookie;
+use crate::modules::hash_pool;
+use crate::middleware::auth::authenticated_claims;
+use actix_web::{ web, HttpResponse, HttpRequest };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPasswordResponse {
+    pub message: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetResponse {
+    pub password_reset: bool
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordChangeResponse {
+    pub password_changed: bool,
+    // Only set when other sessions were revoked, the caller's own tokens are replaced
+    pub new_access_token: Option<String>
+}
+
+pub async fn forgot_password(users: web::Data<Users>, request: web::Json<ForgotPassword>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let request = request.into_inner();
+
+    // Failures only happen for real accounts, so they get the same response as everything else
+    if let Err(error) = repository::block(move || users.forgot_password(request)).await {
+        log::error!("Could not send password reset email: {}", error);
+    }
+
+    Ok(HttpResponse::Ok().json(ForgotPasswordResponse {
+        message: String::from("If an account exists for that email, a password reset link has been sent")
+    }))
+}
+
+pub async fn reset_password(users: web::Data<Users>, reset: web::Json<PasswordReset>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let reset = reset.into_inner();
+
+    let password_reset = hash_pool::run(move || users.reset_password(reset)).await??;
+
+    Ok(HttpResponse::Ok().json(PasswordResetResponse { password_reset }))
+}
+
+pub async fn change_expired_password(users: web::Data<Users>, change: web::Json<ExpiredPasswordChange>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let change = change.into_inner();
+
+    let password_changed = hash_pool::run(move || users.change_expired_password(change)).await??;
+
+    Ok(HttpResponse::Ok().json(PasswordChangeResponse {
+        password_changed,
+        new_access_token: None
+    }))
+}
+
+pub async fn change_password(req: HttpRequest, users: web::Data<Users>, sessions: web::Data<Sessions>, change: web::Json<PasswordChange>) -> Result<HttpResponse, AppError> {
+    let claims = authenticated_claims(&req).expect("Auth middleware must run before changing password");
+    let users = users.get_ref().clone();
+    let change = change.into_inner();
+    let revoke_other_sessions = change.revoke_other_sessions;
+    let user_id = claims.sub;
+
+    let password_changed = hash_pool::run(move || users.change_password(user_id, &change)).await??;
+
+    if !revoke_other_sessions {
+        return Ok(HttpResponse::Ok().json(PasswordChangeResponse {
+            password_changed,
+            new_access_token: None
+        }));
+    }
+
+    let NewTokens { refresh_token, access_token } = repository::revoke_other_sessions(sessions.get_ref().clone(), user_id, claims)
+        .await
+        .map_err(|error| AppError::Internal(format!("Password changed but could not revoke other sessions: {}", error)))?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(refresh_token_cookie(refresh_token))
+        .json(PasswordChangeResponse {
+            password_changed,
+            new_access_token: Some(access_token)
+        }))
+}
@@ -1,18 +1,21 @@
-use crate::models::user::{User, UserManager, NewUser, UserLogin, UserLoggedIn, UserLogout, NewTokens};
-use crate::db::db_connection::{ pg_pool_handler, PgPool };
-use actix_web::{ Responder, web, HttpResponse, http::{Cookie}, HttpRequest, HttpMessage };
-use crate::modules::jwt::{validate_token};
+use crate::models::user::{User, NewUser, UserLogin, UserLoggedIn, UserLogout, NewTokens, LoginOutcome, MfaChallenge, PasswordExpired};
+use crate::models::mfa::MfaVerification;
+use crate::db::repository::{self, Users, Sessions};
+use crate::errors::errors::AppError;
+use actix_web::{ web, HttpResponse, http::Cookie, HttpRequest, HttpMessage };
+use crate::modules::jwt::{decode_token, TokenKind};
+use crate::modules::hash_pool;
 
 #[derive(Serialize)]
 pub struct UsersResponse {
     pub users: Vec<User>
 }
 
-pub async fn get_users(pool: web::Data<PgPool>) -> impl Responder {
-    let pg_pool = pg_pool_handler(pool).expect("Could not connect to DB from get users handler");
-    let all_users = User::get_all(&pg_pool);
-    // @todo make sure response can handle potential failer 
-    HttpResponse::Ok().json(UsersResponse { users: all_users })
+pub async fn get_users(users: web::Data<Users>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let all_users = repository::block(move || users.all()).await?;
+
+    Ok(HttpResponse::Ok().json(UsersResponse { users: all_users }))
 }
 
 #[derive(Serialize)]
@@ -20,11 +23,12 @@ pub struct UserResponse {
     pub user: User
 }
 
-pub async fn get_user(pool: web::Data<PgPool>, id: web::Path<i32>) -> impl Responder {
-    let pg_pool = pg_pool_handler(pool).expect("Could not connect to DB from get user handler");
-    let user = User::get(&pg_pool, *id);
-    // @todo make sure response can handle potential failer 
-    HttpResponse::Ok().json(UserResponse { user })
+pub async fn get_user(users: web::Data<Users>, id: web::Path<i32>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let id = id.into_inner();
+    let user = repository::block(move || users.find(id)).await?;
+
+    Ok(HttpResponse::Ok().json(UserResponse { user }))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -33,30 +37,13 @@ pub struct CreateUserResponse {
     pub email: String
 }
 
-#[derive(Serialize)] 
-pub struct CreateUserError {
-    pub message: String,
-    pub error: String
-}
-
-pub async fn create_user(pool: web::Data<PgPool>, user: web::Json<NewUser>) -> impl Responder {
-    let pg_pool = pg_pool_handler(pool).expect("Could not connect to PG from create user handler");
-    let new_user = User::create(&pg_pool, user);
-    
-    match new_user {
-        Ok(new_user) => {
-            HttpResponse::Ok().json(CreateUserResponse { 
-                name: new_user.name, 
-                email: new_user.email 
-            })
-        }
-        Err(error) => {
-            HttpResponse::Ok().json(CreateUserError { 
-                message: String::from("Failed to create user"),
-                error
-            })
-        }
-    }
+pub async fn create_user(users: web::Data<Users>, user: web::Json<NewUser>) -> Result<HttpResponse, AppError> {
+    let new_user = repository::sign_up(users.get_ref().clone(), user.into_inner()).await?;
+
+    Ok(HttpResponse::Ok().json(CreateUserResponse {
+        name: new_user.name,
+        email: new_user.email
+    }))
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -64,72 +51,81 @@ pub struct UserLoginResponse {
     pub user_logged_in: UserLoggedIn
 }
 
-#[derive(Debug, Serialize)]
-pub struct UserLoginError {
-    pub message: String,
-    pub error: String
-}
-
-pub async fn login_user(pool: web::Data<PgPool>, user: web::Json<UserLogin>) -> impl Responder {
-    
-    let pool = pg_pool_handler(pool).expect("Could not connect to PG from login handler");
-    let logged_in_user = User::login(&pool, user);
-
-    match logged_in_user {
-        Ok(user) => {
-
-            let cookie = Cookie::build("refresh_token", user.clone().refresh_token.unwrap())
-                .domain("http://localhost:3000")
-                .secure(true)
-                .http_only(true)
-                .finish();
-
-            HttpResponse::Ok()
-                .cookie(cookie)
-                .json(UserLoginResponse {
-                    user_logged_in: user
-                })
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MfaRequiredResponse {
+    pub mfa_required: MfaChallenge
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasswordExpiredResponse {
+    pub password_expired: PasswordExpired
+}
+
+pub fn refresh_token_cookie(refresh_token: String) -> Cookie<'static> {
+    Cookie::build("refresh_token", refresh_token)
+        .domain("http://localhost:3000")
+        .secure(true)
+        .http_only(true)
+        .finish()
+}
+
+pub fn logged_in_response(user: UserLoggedIn) -> HttpResponse {
+    HttpResponse::Ok()
+        .cookie(refresh_token_cookie(user.clone().refresh_token.unwrap()))
+        .json(UserLoginResponse {
+            user_logged_in: user
+        })
+}
+
+// Shared by every first-factor login endpoint
+pub fn login_outcome_response(login_outcome: Result<LoginOutcome, AppError>) -> Result<HttpResponse, AppError> {
+    match login_outcome? {
+        LoginOutcome::LoggedIn(user) => Ok(logged_in_response(user)),
+
+        LoginOutcome::MfaRequired(challenge) => {
+            Ok(HttpResponse::Ok().json(MfaRequiredResponse {
+                mfa_required: challenge
+            }))
+        },
+
+        LoginOutcome::PasswordExpired(password_expired) => {
+            Ok(HttpResponse::Ok().json(PasswordExpiredResponse { password_expired }))
         },
 
-        Err(error) => {
-            HttpResponse::Ok().json(UserLoginError {
-                message: String::from("Could not log user in"),
-                error
-            })
-        }
+        // A fixed `account_locked` code and a Retry-After header, so clients can tell a
+        // lockout from bad credentials
+        LoginOutcome::Locked(account_locked) => Err(AppError::Locked(account_locked))
     }
 }
 
-#[derive(Debug, Serialize)]
-pub struct UserLogoutResponse {
-    user_logged_out: bool
+pub async fn login_user(users: web::Data<Users>, sessions: web::Data<Sessions>, user: web::Json<UserLogin>) -> Result<HttpResponse, AppError> {
+    login_outcome_response(repository::login(users.get_ref().clone(), sessions.get_ref().clone(), user.into_inner()).await)
+}
+
+pub async fn verify_mfa_login(users: web::Data<Users>, sessions: web::Data<Sessions>, verification: web::Json<MfaVerification>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let verification = verification.into_inner();
+
+    // Recovery codes are argon2 hashes, checked one by one
+    let authenticated = hash_pool::run({
+        let users = users.clone();
+        move || users.verify_mfa(verification)
+    }).await??;
+
+    login_outcome_response(repository::finish_login(users, sessions.get_ref().clone(), authenticated).await)
 }
 
 #[derive(Debug, Serialize)]
-pub struct UserLogoutError {
-    user_logged_out: bool,
-    message: String,
-    error: String
+pub struct UserLogoutResponse {
+    user_logged_out: bool
 }
 
-pub async fn logout_user(pool: web::Data<PgPool>, user: web::Json<UserLogout>) -> impl Responder {
-    let pool = pg_pool_handler(pool).expect("Could not connect to PG from logout handler");
-    let logout_response = User::logout(&pool, user.clone());
+pub async fn logout_user(sessions: web::Data<Sessions>, user: web::Json<UserLogout>) -> Result<HttpResponse, AppError> {
+    repository::logout(sessions.get_ref().clone(), user.id).await?;
 
-    match logout_response {
-        Ok(_user) => {
-            HttpResponse::Ok().json(UserLogoutResponse {
-                user_logged_out: true
-            })
-        },
-        Err(error) => {
-            HttpResponse::Ok().json(UserLogoutError {
-                user_logged_out: false,
-                message: String::from(format!("Could not log user {} out", user.id)),
-                error: error.to_string()
-            })
-        }
-    }
+    Ok(HttpResponse::Ok().json(UserLogoutResponse {
+        user_logged_out: true
+    }))
 }
 
 #[derive(Serialize, Debug, Clone)]
@@ -142,60 +138,22 @@ pub struct ReauthRequestBody {
     id: i32
 }
 
-pub async fn reauth_user(req: HttpRequest, pool: web::Data<PgPool>, user: web::Json<ReauthRequestBody>) -> impl Responder {
+pub async fn reauth_user(req: HttpRequest, sessions: web::Data<Sessions>, user: web::Json<ReauthRequestBody>) -> Result<HttpResponse, AppError> {
 
     let refresh_token = req
         .cookie("refresh_token")
-        .unwrap()
+        .ok_or_else(|| AppError::Unauthorized("missing_refresh_token", String::from("No refresh_token cookie on request")))?
+        .value()
         .to_string();
 
-    let token_is_still_valid = validate_token(&refresh_token);
-
-    let pg_pool = pg_pool_handler(pool).expect("Could not connect to PG from reauth EP");
-
-    match token_is_still_valid {
-        true => {
-            let refresh_token_is_valid = User::validate_refresh_token(&pg_pool, refresh_token, &user.id);
-
-            match refresh_token_is_valid {
-                Ok(_token) => {
-                    let reauthed_user = User::reauth(&pg_pool, &user.id);
-
-                    match reauthed_user {
-                        Ok(new_tokens) => {
-                            let NewTokens { refresh_token, access_token } = new_tokens;
-                            
-                            let cookie = Cookie::build("refresh_token", refresh_token)
-                                .domain("http://localhost:3000")
-                                .secure(true)
-                                .http_only(true)
-                                .finish();
-
-                            HttpResponse::Ok()
-                                .cookie(cookie)
-                                .json(ReauthResponse {
-                                    new_acccess_token: access_token
-                                }
-                            )
-                        },
-                        Err(error) => {
-                            HttpResponse::Ok().json(ReauthResponse {
-                                new_acccess_token: String::from(format!("failed: {}", error))
-                            })
-                        }
-                    }
-                },
-                Err(error) => {
-                    HttpResponse::Ok().json(ReauthResponse {
-                        new_acccess_token: String::from(format!("failed: {}", error))
-                    })
-                }
-            }
-        },
-        false => {
-            HttpResponse::Ok().json(ReauthResponse {
-                new_acccess_token: String::from("failed")
-            })
-        }
-    }
-}
\ No newline at end of file
+    let refresh_claims = decode_token(&refresh_token, TokenKind::Refresh)
+        .map_err(|_| AppError::invalid_token())?;
+
+    let NewTokens { refresh_token, access_token } = repository::refresh(sessions.get_ref().clone(), user.id, refresh_token, refresh_claims).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(refresh_token_cookie(refresh_token))
+        .json(ReauthResponse {
+            new_acccess_token: access_token
+        }))
+}
@@ -0,0 +1,64 @@
+use crate::models::webauthn::{
+    RegistrationOptions, AuthenticationOptions, AuthenticationOptionsRequest,
+    WebauthnRegistration, WebauthnAuthentication
+};
+use crate::handlers::user::login_outcome_response;
+use crate::db::repository::{self, Users, Sessions};
+use crate::errors::errors::AppError;
+use crate::middleware::auth::authenticated_user_id;
+use actix_web::{ web, HttpResponse, HttpRequest };
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationOptionsResponse {
+    pub webauthn_registration: RegistrationOptions
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationResponse {
+    pub webauthn_registered: bool
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthenticationOptionsResponse {
+    pub webauthn_authentication: AuthenticationOptions
+}
+
+pub async fn registration_options(req: HttpRequest, users: web::Data<Users>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before WebAuthn registration");
+    let users = users.get_ref().clone();
+
+    let webauthn_registration = repository::block(move || users.webauthn_registration_options(user_id)).await?;
+
+    Ok(HttpResponse::Ok().json(RegistrationOptionsResponse { webauthn_registration }))
+}
+
+pub async fn register(req: HttpRequest, users: web::Data<Users>, registration: web::Json<WebauthnRegistration>) -> Result<HttpResponse, AppError> {
+    let user_id = authenticated_user_id(&req).expect("Auth middleware must run before WebAuthn registration");
+    let users = users.get_ref().clone();
+    let registration = registration.into_inner();
+
+    let webauthn_registered = repository::block(move || users.finish_webauthn_registration(user_id, registration)).await?;
+
+    Ok(HttpResponse::Ok().json(RegistrationResponse { webauthn_registered }))
+}
+
+pub async fn authentication_options(users: web::Data<Users>, request: web::Json<AuthenticationOptionsRequest>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let request = request.into_inner();
+
+    let webauthn_authentication = repository::block(move || users.webauthn_authentication_options(request)).await?;
+
+    Ok(HttpResponse::Ok().json(AuthenticationOptionsResponse { webauthn_authentication }))
+}
+
+pub async fn authenticate(users: web::Data<Users>, sessions: web::Data<Sessions>, authentication: web::Json<WebauthnAuthentication>) -> Result<HttpResponse, AppError> {
+    let users = users.get_ref().clone();
+    let authentication = authentication.into_inner();
+
+    let authenticated = repository::block({
+        let users = users.clone();
+        move || users.finish_webauthn_authentication(authentication)
+    }).await?;
+
+    login_outcome_response(repository::finish_login(users, sessions.get_ref().clone(), authenticated).await)
+}
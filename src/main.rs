@@ -1,5 +1,10 @@
+// diesel 1.4's table! and derive macros put their impls inside a const block, which newer
+// compilers warn about for every model
+#![allow(non_local_definitions)]
+
 #[macro_use]
 extern crate diesel;
+extern crate diesel_migrations;
 extern crate dotenv;
 extern crate serde;
 extern crate serde_json;
@@ -12,63 +17,80 @@ extern crate lazy_static;
 
 mod schema;
 mod config;
+mod cli;
 mod tests;
 
 pub mod db;
+pub mod errors;
 pub mod routes;
 pub mod handlers;
 pub mod models;
 pub mod middleware;
 pub mod modules;
 
-use db::db_connection::{establish_connection};
+use db::storage::Storage;
 use routes::user::user_routes;
 use routes::login::login;
 use routes::session::session;
-use handlers::health::status;
+use routes::admin::admin_routes;
+use handlers::health::{status, metrics};
 use middleware::auth;
 
-use actix_web::{App, HttpServer, middleware::Logger, http, dev, Result};
-use actix_web::middleware::errhandlers::{ErrorHandlers, ErrorHandlerResponse};
+use errors::errors::{render_500, json_config};
+
+use actix_web::{App, HttpServer, middleware::Logger, http};
+use actix_web::middleware::errhandlers::ErrorHandlers;
 use actix_cors::Cors;
 
 use crate::config::Config;
 use dotenv::dotenv;
 use env_logger::Env;
 
-fn render_500<B>(mut res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-    res.response_mut()
-       .headers_mut()
-       .insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("Error"));
-    Ok(ErrorHandlerResponse::Response(res))
-}
-
 #[actix_rt::main]
 async fn main() -> Result<(), std::io::Error> {
 
     dotenv().ok(); 
 
-    std::env::set_var("RUST_LOG", "actix_web=info,actix_server=info");
+    std::env::set_var("RUST_LOG", "actix_web=info,actix_server=info,auth_example=info");
     std::env::set_var("RUST_BACKTRACE", "1");
     env_logger::from_env(Env::default().default_filter_or("info")).init(); 
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    // Operator commands such as `migrate` or `create-admin` run and exit instead of serving
+    if let Some(exit_code) = cli::run(&args) {
+        std::process::exit(exit_code);
+    }
+
     let config = Config::from_env().expect("Must set env vars"); 
 
-    println!("Start server {:#?}", config);
+    // Not the whole config, which holds secrets
+    log::info!("Starting server on {}:{}", config.host, config.port);
+
+    // Built once so every worker shares the same `db_pool_max_size` connections
+    let storage = Storage::from_config().unwrap_or_else(|error| {
+        log::error!("Could not set up storage: {}", error);
+        std::process::exit(1);
+    });
+    let with_postgres = storage.has_postgres();
+    let metrics_enabled = config.metrics_enabled;
 
-    HttpServer::new(|| {
+    HttpServer::new(move || {
         let cors = Cors::new().send_wildcard().max_age(3600).finish();
 
         App::new()
             .wrap(ErrorHandlers::new().handler(http::StatusCode::INTERNAL_SERVER_ERROR, render_500))
             .wrap(Logger::default())
             .wrap(Logger::new("%a %t %r %s %b %{Referer}i %{User-Agent}i %T"))
-            .data(establish_connection())
+            .app_data(json_config())
+            .configure(|cfg| storage.configure(cfg))
             .wrap(cors)
             .service(status)
-            .service(login())
-            .service(user_routes().wrap(auth::Auth))
+            .configure(|cfg| if metrics_enabled { cfg.service(metrics); })
+            .service(login(with_postgres))
+            .service(user_routes(with_postgres).wrap(auth::Auth))
             .service(session().wrap(auth::Auth))
+            .service(admin_routes().wrap(auth::Auth))
     })
     .bind(format!("{}:{}", config.host, config.port))?
     .run()
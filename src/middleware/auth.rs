@@ -2,12 +2,27 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use actix_service::{Service, Transform};
-use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, error::ErrorUnauthorized};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpMessage, HttpRequest};
 
 use futures::future::{ok, Ready};
 use futures::Future;
 
-use crate::modules::jwt::{validate_token};
+use crate::errors::errors::AppError;
+use crate::modules::jwt::{decode_token, Claims, TokenKind};
+
+// The claims of the validated access token are stored on the request so
+// handlers behind this middleware know who is calling them
+pub fn authenticated_user_id(req: &HttpRequest) -> Option<i32> {
+    req.extensions()
+        .get::<Claims>()
+        .map(|claims| claims.sub)
+}
+
+pub fn authenticated_claims(req: &HttpRequest) -> Option<Claims> {
+    req.extensions()
+        .get::<Claims>()
+        .cloned()
+}
 
 #[derive(Debug)]
 pub struct Auth;
@@ -68,21 +83,24 @@ where
         match auth_header {
             Some(access_token) => {
 
-                let token_is_still_valid = validate_token(
-                    &access_token.to_str().unwrap().to_string()
+                let access_token_claims = decode_token(
+                    access_token.to_str().unwrap_or_default(),
+                    TokenKind::Access
                 );
             
-                match token_is_still_valid {
-                    true => {
+                match access_token_claims {
+                    Ok(claims) => {
+                        req.extensions_mut().insert(claims);
+
                         let fut = self.service.call(req);
                         Box::pin(async move {
                             let res = fut.await?;
                             Ok(res)
                         })
                     },
-                    false => {
+                    Err(_) => {
                         Box::pin(async { 
-                            Err(ErrorUnauthorized("JWT invalid")) 
+                            Err(Error::from(AppError::invalid_token()))
                         })
                     }
                 }
@@ -90,7 +108,7 @@ where
             },
             None => {
                 Box::pin(async { 
-                    Err(ErrorUnauthorized("No Authorization header on request")) 
+                    Err(Error::from(AppError::Unauthorized("missing_authorization", String::from("No Authorization header on request"))))
                 })
             }
         }
@@ -1 +1,3 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod rate_limit;
+pub mod step_up;
\ No newline at end of file
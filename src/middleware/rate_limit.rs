@@ -0,0 +1,248 @@
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::{Payload, PayloadStream, ServiceRequest, ServiceResponse}, Error, error::{InternalError, PayloadError}, http::{header, HeaderName, HeaderValue, StatusCode}, web, HttpMessage, HttpResponse};
+use bytes::{Bytes, BytesMut};
+
+use futures::future::{ok, Ready};
+use futures::{stream, Future, StreamExt};
+
+use crate::config::Config;
+use crate::errors::errors::{AppError, Problem};
+use crate::modules::jwt::{decode_token, Claims, TokenKind};
+use crate::modules::rate_limit::{Decision, Limit, RateLimits, DEFAULT_STORE};
+
+// Bodies are only buffered to find the account, anything bigger than this isn't a login
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+// Where the middleware finds the account a request is aimed at
+#[derive(Debug, Clone, Copy)]
+pub enum AccountKey {
+    // A top level string field of the JSON body, e.g. "name" on `/app/login`
+    BodyField(&'static str),
+    // The subject of the refresh token cookie
+    RefreshToken,
+    // The caller, for routes behind `auth::Auth`
+    Authenticated
+}
+
+// Every request spends a token from the caller's IP bucket and, when the target
+// account can be found and the IP bucket allowed it, from that account's bucket too.
+// Account buckets can be drained from many IPs to keep the owner out for a while,
+// which is the price of stopping guesses spread over many IPs
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    scope: &'static str,
+    per_ip: Limit,
+    per_account: Option<(AccountKey, Limit)>
+}
+
+impl RateLimit {
+    pub fn from_config(scope: &'static str) -> RateLimit {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        RateLimit {
+            scope,
+            per_ip: Limit {
+                burst: config.rate_limit_ip_burst,
+                per_minute: config.rate_limit_ip_per_minute
+            },
+            per_account: None
+        }
+    }
+
+    pub fn per_account(self, account_key: AccountKey) -> RateLimit {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let limit = Limit {
+            burst: config.rate_limit_account_burst,
+            per_minute: config.rate_limit_account_per_minute
+        };
+
+        RateLimit {
+            per_account: Some((account_key, limit)),
+            ..self
+        }
+    }
+
+    fn check(&self, store: &RateLimits, ip: String, account: Option<String>) -> Decision {
+        let mut buckets = vec![(format!("{}:ip:{}", self.scope, ip), self.per_ip)];
+
+        if let (Some((_, limit)), Some(account)) = (self.per_account, account) {
+            buckets.push((format!("{}:account:{}", self.scope, account), limit));
+        }
+
+        let mut tightest: Option<Decision> = None;
+
+        for (key, limit) in buckets {
+            // A broken store shouldn't take logins down with it
+            let decision = match store.take(&key, &limit) {
+                Ok(decision) => decision,
+                Err(error) => {
+                    log::error!("Could not check rate limit for {}: {}", key, error);
+                    continue;
+                }
+            };
+
+            if !decision.allowed {
+                return decision;
+            }
+
+            if tightest.is_none_or(|tightest| decision.remaining < tightest.remaining) {
+                tightest = Some(decision);
+            }
+        }
+
+        tightest.unwrap_or(Decision {
+            allowed: true,
+            limit: self.per_ip.burst,
+            remaining: self.per_ip.burst,
+            retry_after: 0,
+            reset: 0
+        })
+    }
+}
+
+// Reads the body so the account can be found, then puts it back for the handler
+async fn body_field(req: &mut ServiceRequest, field: &str) -> Result<Option<String>, Error> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+
+        if body.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(PayloadError::Overflow.into());
+        }
+
+        body.extend_from_slice(&chunk);
+    }
+
+    let body: Bytes = body.freeze();
+
+    let account = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|json| json.get(field).and_then(|value| value.as_str()).map(str::to_lowercase));
+
+    let replayed: PayloadStream = Box::pin(stream::once(async move { Ok(body) }));
+    req.set_payload(Payload::Stream(replayed));
+
+    Ok(account)
+}
+
+async fn account(req: &mut ServiceRequest, account_key: AccountKey) -> Result<Option<String>, Error> {
+    match account_key {
+        AccountKey::BodyField(field) => body_field(req, field).await,
+        AccountKey::RefreshToken => Ok(
+            req.cookie("refresh_token")
+                .and_then(|cookie| decode_token(cookie.value(), TokenKind::Refresh).ok())
+                .map(|claims| claims.sub.to_string())
+        ),
+        AccountKey::Authenticated => Ok(
+            req.extensions()
+                .get::<Claims>()
+                .map(|claims| claims.sub.to_string())
+        )
+    }
+}
+
+fn rate_limit_headers(headers: &mut header::HeaderMap, decision: &Decision) {
+    headers.insert(HeaderName::from_static("x-ratelimit-limit"), HeaderValue::from(decision.limit));
+    headers.insert(HeaderName::from_static("x-ratelimit-remaining"), HeaderValue::from(decision.remaining));
+    headers.insert(HeaderName::from_static("x-ratelimit-reset"), HeaderValue::from(decision.reset));
+}
+
+fn too_many_requests(decision: &Decision) -> Error {
+    let mut response = Problem::new(StatusCode::TOO_MANY_REQUESTS, "rate_limited", String::from("Too many requests, try again later"))
+        .with_extension("retry_after", decision.retry_after)
+        .respond(HttpResponse::TooManyRequests().header(header::RETRY_AFTER, decision.retry_after.to_string()));
+
+    rate_limit_headers(response.headers_mut(), decision);
+
+    InternalError::from_response("Too many requests", response).into()
+}
+
+impl<S, B> Transform<S> for RateLimit
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            rate_limit: self.clone()
+        })
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    // Shared with the future, which only calls the inner service once the body has been read
+    service: Rc<RefCell<S>>,
+    rate_limit: RateLimit,
+}
+
+impl<S, B> Service for RateLimitMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let rate_limit = self.rate_limit.clone();
+
+        Box::pin(async move {
+            // The socket address rather than X-Forwarded-For, which any client can set
+            let ip = req.peer_addr()
+                .map(|address| address.ip().to_string())
+                .unwrap_or_else(|| String::from("unknown"));
+
+            let account = match rate_limit.per_account {
+                Some((account_key, _)) => account(&mut req, account_key).await?,
+                None => None
+            };
+
+            let store = req.app_data::<RateLimits>()
+                .map(|store| store.get_ref().clone())
+                .unwrap_or_else(|| DEFAULT_STORE.clone());
+
+            // The Postgres store blocks, so buckets are checked off the event loop
+            let decision = web::block(move || Ok::<_, ()>(rate_limit.check(&store, ip, account)))
+                .await
+                .map_err(|_| AppError::Internal(String::from("Could not check rate limit")))?;
+
+            if !decision.allowed {
+                return Err(too_many_requests(&decision));
+            }
+
+            // The borrow has to end before awaiting, or a second request would find the service borrowed
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            rate_limit_headers(res.headers_mut(), &decision);
+
+            Ok(res)
+        })
+    }
+}
@@ -0,0 +1,124 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_service::{Service, Transform};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, error::InternalError, http::{header, StatusCode}, HttpMessage, HttpResponse};
+
+use futures::future::{ok, Ready};
+use futures::Future;
+
+use crate::config::Config;
+use crate::errors::errors::Problem;
+use crate::modules::jwt::{now, Amr, Claims};
+
+// Sensitive routes wrap this inside `auth::Auth`, which has already validated the
+// access token and left its claims on the request
+#[derive(Debug, Clone)]
+pub struct StepUp {
+    max_age: usize,
+    required_amr: Option<Amr>
+}
+
+impl StepUp {
+    pub fn from_config() -> StepUp {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        StepUp {
+            max_age: config.step_up_max_age_minutes * 60,
+            required_amr: None
+        }
+    }
+
+    pub fn requiring(self, amr: Amr) -> StepUp {
+        StepUp {
+            required_amr: Some(amr),
+            ..self
+        }
+    }
+}
+
+impl<S, B> Transform<S> for StepUp
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = StepUpMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(StepUpMiddleware { service, step_up: self.clone() })
+    }
+}
+
+#[derive(Debug)]
+pub struct StepUpMiddleware<S> {
+    service: S,
+    step_up: StepUp,
+}
+
+impl<S, B> Service for StepUpMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+
+        let authentication = req
+            .extensions()
+            .get::<Claims>()
+            .map(|claims| (claims.amr.clone(), claims.auth_time));
+
+        let StepUp { max_age, required_amr } = self.step_up;
+
+        let authentication_is_sufficient = match authentication {
+            Some((amr, auth_time)) => {
+                let is_recent = now().saturating_sub(auth_time) <= max_age;
+                let has_required_factor = required_amr.is_none_or(|required| amr.contains(&required));
+
+                is_recent && has_required_factor
+            },
+            None => false
+        };
+
+        match authentication_is_sufficient {
+            true => {
+                let fut = self.service.call(req);
+                Box::pin(async move {
+                    let res = fut.await?;
+                    Ok(res)
+                })
+            },
+            false => {
+                // The WWW-Authenticate challenge follows the OAuth step-up convention so
+                // generic clients can react to it as well as our own frontend
+                let response = Problem::new(StatusCode::UNAUTHORIZED, "reauthentication_required", String::from("Log in again to continue"))
+                    .with_extension("max_age", max_age)
+                    .with_extension("required_amr", required_amr)
+                    .respond(HttpResponse::Unauthorized().header(
+                        header::WWW_AUTHENTICATE,
+                        format!("Bearer error=\"insufficient_user_authentication\", max_age={}", max_age)
+                    ));
+
+                Box::pin(async move {
+                    Err(InternalError::from_response("Reauthentication required", response).into())
+                })
+            }
+        }
+    }
+}
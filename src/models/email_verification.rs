@@ -0,0 +1,88 @@
+use chrono::{Duration, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::models::user::User;
+use crate::models::user_token::{UserToken, TokenPurpose};
+use crate::modules::mail::send_template;
+
+#[derive(Debug, Deserialize)]
+pub struct EmailVerification {
+    pub token: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResendVerification {
+    pub email: String
+}
+
+pub trait EmailVerificationManager {
+    fn send_verification_email(pool: &PgConnection, existing_user: &User) -> Result<(), String>;
+    fn verify_email(pool: &PgConnection, verification: EmailVerification) -> Result<bool, AppError>;
+    fn resend_verification_email(pool: &PgConnection, request: ResendVerification) -> Result<(), AppError>;
+}
+
+impl EmailVerificationManager for User {
+
+    fn send_verification_email(pool: &PgConnection, existing_user: &User) -> Result<(), String> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let token = UserToken::issue(
+            pool,
+            existing_user.id,
+            TokenPurpose::EmailVerification,
+            Duration::hours(config.email_verification_ttl_hours)
+        )?;
+
+        send_template(&existing_user.email, &existing_user.locale, "verify_email", &[
+            ("name", existing_user.name.as_str()),
+            ("link", format!("{}/verify-email?token={}", config.app_url, token).as_str()),
+            ("expires_in_hours", config.email_verification_ttl_hours.to_string().as_str())
+        ])
+    }
+
+    fn verify_email(pool: &PgConnection, verification: EmailVerification) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, id, email_verified};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let verified_user_id = UserToken::consume(pool, &verification.token, TokenPurpose::EmailVerification)?;
+
+        diesel::update(users.filter(id.eq(verified_user_id)))
+            .set(email_verified.eq(true))
+            .execute(pool)
+            .map_err(|error| format!("Could not verify email: {}", error))?;
+
+        Ok(true)
+    }
+
+    // Unknown, already verified and throttled addresses are all silently ignored so
+    // the endpoint can't be used to find out which emails have accounts
+    fn resend_verification_email(pool: &PgConnection, request: ResendVerification) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, email};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let existing_user = users
+            .filter(email.eq(&request.email))
+            .get_result::<User>(pool);
+
+        match existing_user {
+            Ok(existing_user) if !existing_user.email_verified => {
+                let last_sent = UserToken::last_issued_at(pool, existing_user.id, TokenPurpose::EmailVerification)?;
+                let throttle = Duration::seconds(config.email_verification_resend_seconds);
+
+                match last_sent {
+                    Some(sent_at) if sent_at + throttle > Utc::now().naive_utc() => Ok(()),
+                    _ => User::send_verification_email(pool, &existing_user).map_err(AppError::from)
+                }
+            },
+            _ => Ok(())
+        }
+    }
+}
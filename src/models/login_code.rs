@@ -0,0 +1,365 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::schema::login_codes;
+use crate::models::user::{User, Authenticated};
+use crate::modules::hash::{hash_password, verify_password};
+use crate::modules::jwt::Amr;
+use crate::modules::mail::send_template;
+use crate::modules::sms::sms_provider_from_config;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginCodeChannel {
+    Email,
+    Sms,
+}
+
+impl LoginCodeChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginCodeChannel::Email => "email",
+            LoginCodeChannel::Sms => "sms",
+        }
+    }
+
+    pub fn amr(&self) -> Amr {
+        match self {
+            LoginCodeChannel::Email => Amr::Email,
+            LoginCodeChannel::Sms => Amr::Sms,
+        }
+    }
+}
+
+#[derive(Debug, Queryable)]
+pub struct LoginCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub channel: String,
+    pub code_hash: String,
+    pub attempts: i32,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="login_codes"]
+pub struct NewLoginCode {
+    pub user_id: i32,
+    pub channel: String,
+    pub code_hash: String,
+    pub expires_at: NaiveDateTime
+}
+
+// One of `email` or `phone` is expected, and picks where the code is sent
+#[derive(Debug, Deserialize)]
+pub struct LoginCodeRequest {
+    pub email: Option<String>,
+    pub phone: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginCodeVerification {
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub code: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PhoneVerification {
+    pub code: String
+}
+
+fn generate_code() -> Result<String, String> {
+    let rng = SystemRandom::new();
+
+    // Rejecting the top of the range keeps every code equally likely
+    loop {
+        let mut bytes = [0u8; 4];
+
+        rng.fill(&mut bytes)
+            .map_err(|_| String::from("Could not generate login code"))?;
+
+        let value = u32::from_be_bytes(bytes);
+
+        if value < u32::MAX - u32::MAX % 1_000_000 {
+            return Ok(format!("{:06}", value % 1_000_000));
+        }
+    }
+}
+
+// Also used for unknown recipients, so it doesn't tell them apart from real accounts
+fn invalid_code() -> AppError {
+    AppError::Unauthorized("invalid_code", String::from("Code is invalid or expired"))
+}
+
+pub fn incorrect_code() -> AppError {
+    AppError::Unauthorized("incorrect_code", String::from("Incorrect code"))
+}
+
+pub fn recipient_required() -> AppError {
+    AppError::BadRequest("recipient_required", String::from("An email or phone number is required"))
+}
+
+impl LoginCode {
+    // A fresh code and its hash. Hashing is argon2, so this runs on the hash pool
+    pub fn generate() -> Result<(String, String), String> {
+        let code = generate_code()?;
+
+        let code_hash = hash_password(code.clone())
+            .map_err(|_| String::from("Could not hash login code"))?;
+
+        Ok((code, code_hash))
+    }
+
+    // Runs on the hash pool
+    pub fn matches(&self, code: &str) -> bool {
+        verify_password(self.code_hash.clone(), code.trim().to_string()).unwrap_or(false)
+    }
+
+    // Stores the hash of a code from `generate`, any earlier unused code for the same
+    // channel stops working
+    pub fn issue(pool: &PgConnection, for_user_id: i32, code_channel: LoginCodeChannel, new_code_hash: String, ttl: Duration) -> Result<(), String> {
+        use crate::schema::login_codes::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let now = Utc::now().naive_utc();
+
+        let new_code = NewLoginCode {
+            user_id: for_user_id,
+            channel: code_channel.as_str().to_string(),
+            code_hash: new_code_hash,
+            expires_at: now + ttl
+        };
+
+        diesel::update(
+                login_codes
+                    .filter(user_id.eq(for_user_id))
+                    .filter(channel.eq(code_channel.as_str()))
+                    .filter(used_at.is_null())
+            )
+            .set(used_at.eq(now))
+            .execute(pool)
+            .map_err(|error| format!("Could not revoke previous codes: {}", error))?;
+
+        diesel::insert_into(login_codes)
+            .values(&new_code)
+            .execute(pool)
+            .map_err(|error| format!("Could not store login code: {}", error))?;
+
+        Ok(())
+    }
+
+    // Every guess counts against the code before it is checked with `matches`, so
+    // parallel requests can't get more than `max_attempts` guesses between them
+    pub fn attempt(pool: &PgConnection, for_user_id: i32, code_channel: LoginCodeChannel, max_attempts: i32) -> Result<LoginCode, AppError> {
+        use crate::schema::login_codes::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let current_code = login_codes
+            .filter(user_id.eq(for_user_id))
+            .filter(channel.eq(code_channel.as_str()))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .order(created_at.desc())
+            .first::<LoginCode>(pool)
+            .map_err(|_| invalid_code())?;
+
+        let counted = diesel::update(
+                login_codes
+                    .filter(id.eq(current_code.id))
+                    .filter(attempts.lt(max_attempts))
+            )
+            .set(attempts.eq(attempts + 1))
+            .execute(pool)
+            .map_err(|error| format!("Could not record attempt: {}", error))?;
+
+        if counted == 0 {
+            return Err(AppError::Unauthorized("too_many_attempts", String::from("Too many attempts, request a new code")));
+        }
+
+        Ok(current_code)
+    }
+
+    // False when a parallel request already used the code
+    pub fn spend(pool: &PgConnection, login_code_id: i32) -> Result<bool, String> {
+        use crate::schema::login_codes::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let spent = diesel::update(
+                login_codes
+                    .filter(id.eq(login_code_id))
+                    .filter(used_at.is_null())
+            )
+            .set(used_at.eq(Utc::now().naive_utc()))
+            .execute(pool)
+            .map_err(|error| format!("Could not use login code: {}", error))?;
+
+        Ok(spent == 1)
+    }
+}
+
+// Phone numbers only count once verified, anyone can sign up with someone else's
+pub fn find_recipient(pool: &PgConnection, request_email: &Option<String>, request_phone: &Option<String>) -> Result<(Option<User>, LoginCodeChannel), AppError> {
+    use crate::schema::users::dsl::{users, email, phone, phone_verified};
+    use crate::diesel::QueryDsl;
+    use crate::diesel::ExpressionMethods;
+
+    match (request_email, request_phone) {
+        (Some(request_email), _) => {
+            let existing_user = users
+                .filter(email.eq(request_email))
+                .get_result::<User>(pool)
+                .ok();
+
+            Ok((existing_user, LoginCodeChannel::Email))
+        },
+        (None, Some(request_phone)) => {
+            let existing_user = users
+                .filter(phone.eq(request_phone))
+                .filter(phone_verified.eq(true))
+                .get_result::<User>(pool)
+                .ok();
+
+            Ok((existing_user, LoginCodeChannel::Sms))
+        },
+        (None, None) => Err(recipient_required())
+    }
+}
+
+// Hashing happens on the hash pool in between these steps, so they are split where
+// it goes
+pub trait LoginCodeManager {
+    fn send_login_code(pool: &PgConnection, existing_user: &User, code_channel: LoginCodeChannel, code: &str, code_hash: String) -> Result<(), String>;
+    fn start_login_code_verification(pool: &PgConnection, verification: &LoginCodeVerification) -> Result<(User, LoginCodeChannel, LoginCode), AppError>;
+    fn finish_login_code_verification(pool: &PgConnection, existing_user: User, code_channel: LoginCodeChannel, login_code: LoginCode) -> Result<Authenticated, AppError>;
+    fn send_phone_verification_code(pool: &PgConnection, user_id: i32, code: &str, code_hash: String) -> Result<(), AppError>;
+    fn start_phone_verification(pool: &PgConnection, user_id: i32) -> Result<LoginCode, AppError>;
+    fn finish_phone_verification(pool: &PgConnection, user_id: i32, login_code: LoginCode) -> Result<(), AppError>;
+}
+
+impl LoginCodeManager for User {
+
+    fn send_login_code(pool: &PgConnection, existing_user: &User, code_channel: LoginCodeChannel, code: &str, code_hash: String) -> Result<(), String> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let ttl = Duration::minutes(config.login_code_ttl_minutes);
+
+        LoginCode::issue(pool, existing_user.id, code_channel, code_hash, ttl)?;
+
+        match code_channel {
+            LoginCodeChannel::Email => send_template(&existing_user.email, &existing_user.locale, "login_code", &[
+                ("name", existing_user.name.as_str()),
+                ("code", code),
+                ("expires_in_minutes", config.login_code_ttl_minutes.to_string().as_str())
+            ]),
+            LoginCodeChannel::Sms => {
+                let body = format!("{} is your {} login code", code, config.totp_issuer);
+                let to = existing_user.phone.clone().unwrap_or_default();
+
+                sms_provider_from_config(&config).send(&to, &body)
+            }
+        }
+    }
+
+    fn start_login_code_verification(pool: &PgConnection, verification: &LoginCodeVerification) -> Result<(User, LoginCodeChannel, LoginCode), AppError> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let (existing_user, code_channel) = match find_recipient(pool, &verification.email, &verification.phone)? {
+            (Some(existing_user), code_channel) => (existing_user, code_channel),
+            (None, _) => return Err(invalid_code())
+        };
+
+        let login_code = LoginCode::attempt(pool, existing_user.id, code_channel, config.login_code_max_attempts)?;
+
+        Ok((existing_user, code_channel, login_code))
+    }
+
+    // Once `LoginCode::matches` accepted the code
+    fn finish_login_code_verification(pool: &PgConnection, existing_user: User, code_channel: LoginCodeChannel, login_code: LoginCode) -> Result<Authenticated, AppError> {
+        use crate::schema::users::dsl::{users, id, email_verified};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        if !LoginCode::spend(pool, login_code.id)? {
+            return Err(incorrect_code());
+        }
+
+        // Like a magic link, an emailed code proves the address is theirs
+        let existing_user = match code_channel {
+            LoginCodeChannel::Email if !existing_user.email_verified => {
+                diesel::update(users.filter(id.eq(existing_user.id)))
+                    .set(email_verified.eq(true))
+                    .get_result::<User>(pool)
+                    .map_err(|error| format!("Could not verify email: {}", error))?
+            },
+            _ => existing_user
+        };
+
+        Ok(Authenticated::FirstFactor(existing_user, code_channel.amr()))
+    }
+
+    // The code goes out on the SMS channel, which can't log in until the number is
+    // verified, and a verified number can't be verified again
+    fn send_phone_verification_code(pool: &PgConnection, user_id: i32, code: &str, code_hash: String) -> Result<(), AppError> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let existing_user = User::find(pool, user_id)?;
+
+        let to = match (existing_user.phone, existing_user.phone_verified) {
+            (None, _) => return Err(AppError::BadRequest("phone_required", String::from("No phone number to verify"))),
+            (Some(_), true) => return Err(AppError::Conflict("phone_already_verified", String::from("Phone number is already verified"))),
+            (Some(to), false) => to
+        };
+
+        LoginCode::issue(pool, user_id, LoginCodeChannel::Sms, code_hash, Duration::minutes(config.login_code_ttl_minutes))?;
+
+        let body = format!("{} is your {} verification code", code, config.totp_issuer);
+
+        Ok(sms_provider_from_config(&config).send(&to, &body)?)
+    }
+
+    fn start_phone_verification(pool: &PgConnection, user_id: i32) -> Result<LoginCode, AppError> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        LoginCode::attempt(pool, user_id, LoginCodeChannel::Sms, config.login_code_max_attempts)
+    }
+
+    // Once `LoginCode::matches` accepted the code
+    fn finish_phone_verification(pool: &PgConnection, user_id: i32, login_code: LoginCode) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, phone_verified};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        if !LoginCode::spend(pool, login_code.id)? {
+            return Err(incorrect_code());
+        }
+
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(phone_verified.eq(true))
+            .execute(pool)
+            .map_err(|error| format!("Could not verify phone number: {}", error))?;
+
+        Ok(())
+    }
+}
+
+#[test]
+fn codes_are_six_digits() {
+    for _ in 0..100 {
+        let code = generate_code().unwrap();
+
+        assert_eq!(code.len(), 6);
+        assert!(code.chars().all(|c| c.is_ascii_digit()));
+    }
+}
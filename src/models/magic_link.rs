@@ -0,0 +1,91 @@
+use chrono::Duration;
+use diesel::{PgConnection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::models::user::{User, Authenticated};
+use crate::models::user_token::{UserToken, TokenPurpose};
+use crate::modules::jwt::Amr;
+use crate::modules::mail::send_template;
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MagicLinkVerification {
+    pub token: String
+}
+
+fn ensure_enabled() -> Result<(), AppError> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    match config.magic_link_enabled {
+        true => Ok(()),
+        false => Err(AppError::Forbidden("magic_link_disabled", String::from("Magic link login is disabled")))
+    }
+}
+
+pub trait MagicLinkManager {
+    fn send_magic_link(pool: &PgConnection, request: MagicLinkRequest) -> Result<(), AppError>;
+    fn verify_magic_link(pool: &PgConnection, verification: MagicLinkVerification) -> Result<Authenticated, AppError>;
+}
+
+impl MagicLinkManager for User {
+
+    // Only fails when the feature is off, unknown emails and delivery problems are
+    // swallowed so this can't be used to find accounts
+    fn send_magic_link(pool: &PgConnection, request: MagicLinkRequest) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, email};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        ensure_enabled()?;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let existing_user = users
+            .filter(email.eq(&request.email))
+            .get_result::<User>(pool);
+
+        if let Ok(existing_user) = existing_user {
+            let sent = UserToken::issue(
+                pool,
+                existing_user.id,
+                TokenPurpose::MagicLink,
+                Duration::minutes(config.magic_link_ttl_minutes)
+            ).and_then(|token| send_template(&existing_user.email, &existing_user.locale, "magic_link", &[
+                ("name", existing_user.name.as_str()),
+                ("link", format!("{}/magic-link?token={}", config.app_url, token).as_str()),
+                ("expires_in_minutes", config.magic_link_ttl_minutes.to_string().as_str())
+            ]));
+
+            if let Err(error) = sent {
+                log::error!("Could not send magic link: {}", error);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify_magic_link(pool: &PgConnection, verification: MagicLinkVerification) -> Result<Authenticated, AppError> {
+        use crate::schema::users::dsl::{users, id, email_verified};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        ensure_enabled()?;
+
+        let user_id = UserToken::consume(pool, &verification.token, TokenPurpose::MagicLink)?;
+
+        // Opening the link proves the address is theirs just as well as the verification email would
+        let existing_user = diesel::update(users.filter(id.eq(user_id)))
+            .set(email_verified.eq(true))
+            .get_result::<User>(pool)
+            .map_err(|_| AppError::user_not_found())?;
+
+        Ok(Authenticated::FirstFactor(existing_user, Amr::Email))
+    }
+}
@@ -0,0 +1,228 @@
+use chrono::{Duration, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::models::user::{User, Authenticated, MfaChallenge, AccountLocked};
+use crate::models::recovery_code::RecoveryCode;
+use crate::models::webauthn::WebauthnCredential;
+use crate::modules::jwt::{jwt_factory, decode_token, expires_in, now, Amr, Claims, TokenKind};
+use crate::modules::totp;
+
+// Wrong codes a challenge survives before the password has to be entered again
+const MAX_CHALLENGE_FAILURES: i32 = 3;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnrolment {
+    pub secret: String,
+    pub otpauth_uri: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpConfirmation {
+    pub code: String
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpEnabled {
+    pub totp_enabled: bool,
+    pub recovery_codes: Vec<String>
+}
+
+// One of `code` (from the authenticator app) or `recovery_code` is expected
+#[derive(Debug, Deserialize)]
+pub struct MfaVerification {
+    pub challenge_token: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>
+}
+
+pub trait MfaManager {
+    fn mfa_methods(pool: &PgConnection, existing_user: &User) -> Result<Vec<String>, AppError>;
+    fn mfa_challenge(existing_user: &User, first_factor: Amr, methods: Vec<String>) -> MfaChallenge;
+    fn start_totp_enrolment(pool: &PgConnection, user_id: i32) -> Result<TotpEnrolment, AppError>;
+    fn confirm_totp_enrolment(pool: &PgConnection, user_id: i32, confirmation: TotpConfirmation) -> Result<TotpEnabled, AppError>;
+    fn regenerate_recovery_codes(pool: &PgConnection, user_id: i32) -> Result<Vec<String>, AppError>;
+    fn verify_mfa(pool: &PgConnection, verification: MfaVerification) -> Result<Authenticated, AppError>;
+}
+
+impl MfaManager for User {
+
+    // An error means a second factor may be missing from the list, so it fails the
+    // login rather than letting the password alone through
+    fn mfa_methods(pool: &PgConnection, existing_user: &User) -> Result<Vec<String>, AppError> {
+        let mut methods = vec![];
+
+        if existing_user.totp_enabled {
+            methods.push(String::from("totp"));
+            methods.push(String::from("recovery_code"));
+        }
+
+        if !WebauthnCredential::for_user(pool, existing_user.id)?.is_empty() {
+            methods.push(String::from("webauthn"));
+        }
+
+        Ok(methods)
+    }
+
+    fn mfa_challenge(existing_user: &User, first_factor: Amr, methods: Vec<String>) -> MfaChallenge {
+        // Only proves the first factor succeeded, it is useless without the second one
+        let challenge_claims = Claims {
+            sub: existing_user.id,
+            exp: expires_in(Duration::minutes(5)),
+            kind: TokenKind::MfaChallenge,
+            amr: vec![first_factor],
+            auth_time: now()
+        };
+
+        MfaChallenge {
+            challenge_token: jwt_factory(challenge_claims),
+            methods
+        }
+    }
+
+    fn start_totp_enrolment(pool: &PgConnection, user_id: i32) -> Result<TotpEnrolment, AppError> {
+        use crate::schema::users::dsl::{users, id, totp_secret};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let existing_user = User::find(pool, user_id)?;
+
+        if existing_user.totp_enabled {
+            return Err(AppError::Conflict("totp_already_enabled", String::from("TOTP is already enabled")));
+        }
+
+        let secret = totp::generate_secret()?;
+
+        // The secret is stored straight away but only takes effect once a first code confirms it
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(totp_secret.eq(&secret))
+            .execute(pool)
+            .map_err(|error| format!("Could not store TOTP secret: {}", error))?;
+
+        Ok(TotpEnrolment {
+            otpauth_uri: totp::otpauth_uri(&config.totp_issuer, &existing_user.email, &secret),
+            secret
+        })
+    }
+
+    fn confirm_totp_enrolment(pool: &PgConnection, user_id: i32, confirmation: TotpConfirmation) -> Result<TotpEnabled, AppError> {
+        use crate::schema::users::dsl::{users, id, totp_enabled, totp_last_step};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let existing_user = User::find(pool, user_id)?;
+
+        if existing_user.totp_enabled {
+            return Err(AppError::Conflict("totp_already_enabled", String::from("TOTP is already enabled")));
+        }
+
+        match existing_user.totp_secret {
+            Some(secret) => {
+                match totp::matching_step(&secret, &confirmation.code, Utc::now().timestamp()) {
+                    Some(step) => {
+                        // The confirmation code can't be used again to log in
+                        diesel::update(users.filter(id.eq(user_id)))
+                            .set((totp_enabled.eq(true), totp_last_step.eq(step)))
+                            .execute(pool)
+                            .map_err(|error| format!("Could not enable TOTP: {}", error))?;
+
+                        Ok(TotpEnabled {
+                            totp_enabled: true,
+                            recovery_codes: RecoveryCode::regenerate(pool, user_id)?
+                        })
+                    },
+                    None => Err(AppError::BadRequest("incorrect_code", String::from("Incorrect code")))
+                }
+            },
+            None => Err(AppError::BadRequest("totp_enrolment_not_started", String::from("TOTP enrolment has not been started")))
+        }
+    }
+
+    fn regenerate_recovery_codes(pool: &PgConnection, user_id: i32) -> Result<Vec<String>, AppError> {
+        let existing_user = User::find(pool, user_id)?;
+
+        match existing_user.totp_enabled {
+            true => RecoveryCode::regenerate(pool, user_id).map_err(AppError::from),
+            false => Err(AppError::BadRequest("totp_not_enabled", String::from("TOTP is not enabled")))
+        }
+    }
+
+    fn verify_mfa(pool: &PgConnection, verification: MfaVerification) -> Result<Authenticated, AppError> {
+        use crate::schema::users::dsl::{users, id};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let challenge = decode_token(&verification.challenge_token, TokenKind::MfaChallenge)
+            .map_err(|_| AppError::invalid_token())?;
+
+        let existing_user = users
+            .filter(id.eq(challenge.sub))
+            .get_result::<User>(pool)
+            .map_err(|_| AppError::invalid_token())?;
+
+        if let Some(until) = existing_user.locked_until.filter(|until| *until > Utc::now().naive_utc()) {
+            return Err(AppError::Locked(AccountLocked::until(until)));
+        }
+
+        // Wrong codes count as failed logins, which only a new password login resets
+        if existing_user.failed_login_attempts >= MAX_CHALLENGE_FAILURES {
+            return Err(AppError::invalid_token());
+        }
+
+        if !existing_user.totp_enabled {
+            return Err(AppError::BadRequest("mfa_not_enabled", String::from("MFA is not enabled")));
+        }
+
+        let code_is_valid = match (&existing_user.totp_secret, &verification.code, &verification.recovery_code) {
+            (Some(secret), Some(code), _) => match totp::matching_step(secret, code, Utc::now().timestamp()) {
+                Some(step) => User::accept_totp_step(pool, existing_user.id, step)?,
+                None => false
+            },
+            (_, None, Some(recovery_code)) => RecoveryCode::redeem(pool, existing_user.id, recovery_code).map_err(AppError::Internal)?,
+            _ => false
+        };
+
+        match code_is_valid {
+            true => {
+                if existing_user.failed_login_attempts > 0 {
+                    User::unlock(pool, existing_user.id)?;
+                }
+
+                let mut amr = challenge.amr;
+                amr.push(Amr::Otp);
+
+                Ok(Authenticated::AllFactors(existing_user, amr))
+            },
+            false => match User::record_failed_login(pool, existing_user.id).map_err(AppError::Internal)? {
+                Some(until) => Err(AppError::Locked(AccountLocked::until(until))),
+                None => Err(AppError::Unauthorized("incorrect_code", String::from("Incorrect code")))
+            }
+        }
+    }
+}
+
+impl User {
+    // Moves the last accepted step forward, false when the step was already used,
+    // including by a request racing this one
+    fn accept_totp_step(pool: &PgConnection, user_id: i32, step: i64) -> Result<bool, AppError> {
+        use crate::schema::users::dsl::{users, id, totp_last_step};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+        use crate::diesel::BoolExpressionMethods;
+
+        let updated = diesel::update(
+                users
+                    .filter(id.eq(user_id))
+                    .filter(totp_last_step.is_null().or(totp_last_step.lt(step)))
+            )
+            .set(totp_last_step.eq(step))
+            .execute(pool)
+            .map_err(|error| format!("Could not record TOTP step: {}", error))?;
+
+        Ok(updated == 1)
+    }
+}
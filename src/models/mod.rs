@@ -1 +1,11 @@
-pub mod user;
\ No newline at end of file
+pub mod user;
+pub mod mfa;
+pub mod recovery_code;
+pub mod user_token;
+pub mod webauthn;
+pub mod email_verification;
+pub mod password;
+pub mod password_history;
+pub mod magic_link;
+pub mod login_code;
+pub mod signing_key;
\ No newline at end of file
@@ -0,0 +1,174 @@
+use chrono::{Duration, Utc};
+use diesel::{Connection, PgConnection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::models::user::{User, PasswordExpired, AccountLocked};
+use crate::models::user_token::{UserToken, TokenPurpose};
+use crate::modules::mail::send_template;
+use crate::modules::hash::{verify_password, password_matches};
+use crate::modules::jwt::{jwt_factory, decode_token, expires_in, now, Amr, Claims, TokenKind};
+use crate::modules::password_policy::validate_password;
+
+#[derive(Debug, Deserialize)]
+pub struct ForgotPassword {
+    pub email: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordChange {
+    pub current_password: String,
+    pub new_password: String,
+    #[serde(default)]
+    pub revoke_other_sessions: bool
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExpiredPasswordChange {
+    pub change_token: String,
+    pub new_password: String
+}
+
+fn password_unchanged() -> AppError {
+    AppError::BadRequest("password_unchanged", String::from("New password must be different from the current one"))
+}
+
+// A token can outlive its user, which makes it as good as expired
+fn deleted_user_token(error: AppError) -> AppError {
+    match error {
+        AppError::NotFound(..) => AppError::invalid_token(),
+        error => error
+    }
+}
+
+pub trait PasswordManager {
+    fn forgot_password(pool: &PgConnection, request: ForgotPassword) -> Result<(), String>;
+    fn reset_password(pool: &PgConnection, reset: PasswordReset) -> Result<bool, AppError>;
+    fn change_password(pool: &PgConnection, user_id: i32, change: &PasswordChange) -> Result<bool, AppError>;
+    fn password_change_token(existing_user: &User) -> PasswordExpired;
+    fn change_expired_password(pool: &PgConnection, change: ExpiredPasswordChange) -> Result<bool, AppError>;
+}
+
+impl PasswordManager for User {
+
+    // Unknown emails are silently ignored so this can't be used to find accounts
+    fn forgot_password(pool: &PgConnection, request: ForgotPassword) -> Result<(), String> {
+        use crate::schema::users::dsl::{users, email};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        let existing_user = users
+            .filter(email.eq(&request.email))
+            .get_result::<User>(pool);
+
+        match existing_user {
+            Ok(existing_user) => {
+                let token = UserToken::issue(
+                    pool,
+                    existing_user.id,
+                    TokenPurpose::PasswordReset,
+                    Duration::minutes(config.password_reset_ttl_minutes)
+                )?;
+
+                send_template(&existing_user.email, &existing_user.locale, "password_reset", &[
+                    ("name", existing_user.name.as_str()),
+                    ("link", format!("{}/reset-password?token={}", config.app_url, token).as_str()),
+                    ("expires_in_minutes", config.password_reset_ttl_minutes.to_string().as_str())
+                ])
+            },
+            Err(_) => Ok(())
+        }
+    }
+
+    fn reset_password(pool: &PgConnection, reset: PasswordReset) -> Result<bool, AppError> {
+        // Checked before the token is used up so a rejected password can be retried
+        let existing_user = User::find(pool, UserToken::peek(pool, &reset.token, TokenPurpose::PasswordReset)?)
+            .map_err(deleted_user_token)?;
+        validate_password(&reset.password, &[&existing_user.name, &existing_user.email])?;
+
+        // The token is only spent if the password is actually changed, a reused
+        // password or a failed update rolls it back
+        pool.transaction::<_, AppError, _>(|| {
+            let user_id = UserToken::consume(pool, &reset.token, TokenPurpose::PasswordReset)?;
+
+            User::update_password(pool, user_id, reset.password)?;
+
+            // Whoever knew the old password may still hold a session
+            User::revoke_sessions(pool, user_id)
+                .map_err(|error| format!("Could not revoke sessions: {}", error))?;
+
+            Ok(true)
+        })
+    }
+
+    // Wrong current passwords count towards the same lockout as wrong passwords at login
+    fn change_password(pool: &PgConnection, user_id: i32, change: &PasswordChange) -> Result<bool, AppError> {
+        let existing_user = User::find(pool, user_id)?;
+
+        if let Some(until) = existing_user.locked_until.filter(|until| *until > Utc::now().naive_utc()) {
+            return Err(AppError::Locked(AccountLocked::until(until)));
+        }
+
+        if !password_matches(existing_user.password, change.current_password.to_string()) {
+            return match User::record_failed_login(pool, user_id)? {
+                Some(until) => Err(AppError::Locked(AccountLocked::until(until))),
+                None => Err(AppError::BadRequest("incorrect_password", String::from("Incorrect password")))
+            };
+        }
+
+        if existing_user.failed_login_attempts > 0 {
+            User::unlock(pool, user_id)?;
+        }
+
+        if change.new_password == change.current_password {
+            return Err(password_unchanged());
+        }
+
+        User::update_password(pool, user_id, change.new_password.to_string())?;
+
+        Ok(true)
+    }
+
+    fn password_change_token(existing_user: &User) -> PasswordExpired {
+        let change_claims = Claims {
+            sub: existing_user.id,
+            exp: expires_in(Duration::minutes(10)),
+            kind: TokenKind::PasswordChange,
+            amr: vec![Amr::Pwd],
+            auth_time: now()
+        };
+
+        PasswordExpired {
+            change_token: jwt_factory(change_claims)
+        }
+    }
+
+    fn change_expired_password(pool: &PgConnection, change: ExpiredPasswordChange) -> Result<bool, AppError> {
+        let claims = decode_token(&change.change_token, TokenKind::PasswordChange)
+            .map_err(|_| AppError::invalid_token())?;
+        let existing_user = User::find(pool, claims.sub)
+            .map_err(deleted_user_token)?;
+
+        // Once the password has been changed the token is spent
+        if existing_user.password_changed_at.timestamp() as usize > claims.auth_time {
+            return Err(AppError::invalid_token());
+        }
+
+        if verify_password(existing_user.password, change.new_password.clone()).unwrap_or(false) {
+            return Err(password_unchanged());
+        }
+
+        User::update_password(pool, claims.sub, change.new_password)?;
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::NaiveDateTime;
+use diesel::{PgConnection, RunQueryDsl};
+
+use crate::schema::password_history;
+
+#[derive(Debug, Queryable)]
+pub struct PasswordHistory {
+    pub id: i32,
+    pub user_id: i32,
+    pub password_hash: String,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="password_history"]
+pub struct NewPasswordHistory {
+    pub user_id: i32,
+    pub password_hash: String
+}
+
+impl PasswordHistory {
+    // Hashes of the `limit` passwords the user had most recently before their current one
+    pub fn recent(pool: &PgConnection, for_user_id: i32, limit: usize) -> Result<Vec<String>, String> {
+        use crate::schema::password_history::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        password_history
+            .filter(user_id.eq(for_user_id))
+            .order((created_at.desc(), id.desc()))
+            .limit(limit as i64)
+            .select(password_hash)
+            .load::<String>(pool)
+            .map_err(|error| format!("Could not load password history: {}", error))
+    }
+
+    // Stores the password being replaced and forgets anything older than the last `keep`
+    pub fn record(pool: &PgConnection, for_user_id: i32, replaced_hash: String, keep: usize) -> Result<(), diesel::result::Error> {
+        use crate::schema::password_history::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        diesel::insert_into(password_history)
+            .values(&NewPasswordHistory { user_id: for_user_id, password_hash: replaced_hash })
+            .execute(pool)?;
+
+        let kept_ids = password_history
+            .filter(user_id.eq(for_user_id))
+            .order((created_at.desc(), id.desc()))
+            .limit(keep as i64)
+            .select(id)
+            .load::<i32>(pool)?;
+
+        diesel::delete(
+                password_history
+                    .filter(user_id.eq(for_user_id))
+                    .filter(id.ne_all(kept_ids))
+            )
+            .execute(pool)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, PgConnection, RunQueryDsl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::schema::mfa_recovery_codes;
+use crate::modules::hash::{hash_password, verify_password};
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, Queryable)]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<NaiveDateTime>
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="mfa_recovery_codes"]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String
+}
+
+fn generate_code() -> Result<String, String> {
+    let mut bytes = [0u8; 5];
+
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| String::from("Could not generate recovery code"))?;
+
+    let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes).to_lowercase();
+
+    Ok(format!("{}-{}", &code[..4], &code[4..]))
+}
+
+// Users copy these off a printout, so be lenient about dashes, spacing and case
+fn normalise(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+impl RecoveryCode {
+    // Replaces any existing codes, so a regenerated set invalidates the old one
+    pub fn regenerate(pool: &PgConnection, for_user_id: i32) -> Result<Vec<String>, String> {
+        use crate::schema::mfa_recovery_codes::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| generate_code())
+            .collect::<Result<Vec<String>, String>>()?;
+
+        let new_codes = codes
+            .iter()
+            .map(|code| {
+                hash_password(normalise(code))
+                    .map(|hash| NewRecoveryCode { user_id: for_user_id, code_hash: hash })
+                    .map_err(|_| String::from("Could not hash recovery code"))
+            })
+            .collect::<Result<Vec<NewRecoveryCode>, String>>()?;
+
+        pool.transaction::<_, diesel::result::Error, _>(|| {
+            diesel::delete(mfa_recovery_codes.filter(user_id.eq(for_user_id)))
+                .execute(pool)?;
+
+            diesel::insert_into(mfa_recovery_codes)
+                .values(&new_codes)
+                .execute(pool)
+        })
+        .map_err(|error| format!("Could not store recovery codes: {}", error))?;
+
+        Ok(codes)
+    }
+
+    pub fn redeem(pool: &PgConnection, for_user_id: i32, code: &str) -> Result<bool, String> {
+        use crate::schema::mfa_recovery_codes::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let unused_codes = mfa_recovery_codes
+            .filter(user_id.eq(for_user_id))
+            .filter(used_at.is_null())
+            .load::<RecoveryCode>(pool)
+            .map_err(|error| format!("Could not load recovery codes: {}", error))?;
+
+        let code = normalise(code);
+
+        let matching_code = unused_codes
+            .into_iter()
+            .find(|recovery_code| {
+                verify_password(recovery_code.code_hash.clone(), code.clone()).unwrap_or(false)
+            });
+
+        match matching_code {
+            Some(recovery_code) => {
+                // Filtering on used_at again stops two concurrent logins spending the same code
+                let spent = diesel::update(
+                        mfa_recovery_codes
+                            .filter(id.eq(recovery_code.id))
+                            .filter(used_at.is_null())
+                    )
+                    .set(used_at.eq(Utc::now().naive_utc()))
+                    .execute(pool)
+                    .map_err(|error| format!("Could not use recovery code: {}", error))?;
+
+                Ok(spent == 1)
+            },
+            None => Ok(false)
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::thread;
+use std::time;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::Config;
+use crate::db::db_connection::PgPool;
+use crate::modules::jwt::{set_signing_keys, RingKey};
+use crate::schema::signing_keys;
+
+#[derive(Debug, Queryable, Serialize)]
+pub struct SigningKey {
+    pub id: i32,
+    pub kid: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: NaiveDateTime,
+    pub retired_at: Option<NaiveDateTime>
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="signing_keys"]
+pub struct NewSigningKey {
+    pub kid: String,
+    pub secret: String
+}
+
+fn random_bytes(length: usize) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![0u8; length];
+
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| String::from("Could not generate signing key"))?;
+
+    Ok(bytes)
+}
+
+impl SigningKey {
+    // Keys tokens can still be verified with, newest first
+    pub fn active(pool: &PgConnection) -> Result<Vec<SigningKey>, String> {
+        use crate::schema::signing_keys::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        signing_keys
+            .filter(retired_at.is_null())
+            .order(created_at.desc())
+            .load::<SigningKey>(pool)
+            .map_err(|error| format!("Could not load signing keys: {}", error))
+    }
+
+    pub fn all(pool: &PgConnection) -> Result<Vec<SigningKey>, String> {
+        use crate::schema::signing_keys::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        signing_keys
+            .order(created_at.desc())
+            .load::<SigningKey>(pool)
+            .map_err(|error| format!("Could not load signing keys: {}", error))
+    }
+
+    // Adds a key that takes over signing once every server has picked it up. Older keys
+    // keep verifying the tokens they signed until they're retired
+    pub fn rotate(pool: &PgConnection) -> Result<SigningKey, String> {
+        use crate::schema::signing_keys::dsl::*;
+
+        let new_key = NewSigningKey {
+            kid: random_bytes(8)?.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            secret: base64::encode(&random_bytes(32)?)
+        };
+
+        diesel::insert_into(signing_keys)
+            .values(&new_key)
+            .get_result::<SigningKey>(pool)
+            .map_err(|error| format!("Could not store signing key: {}", error))
+    }
+
+    // Tokens signed with a retired key stop validating once servers refresh their keys
+    pub fn retire(pool: &PgConnection, key_id: &str) -> Result<SigningKey, String> {
+        use crate::schema::signing_keys::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        diesel::update(signing_keys.filter(kid.eq(key_id)).filter(retired_at.is_null()))
+            .set(retired_at.eq(Utc::now().naive_utc()))
+            .get_result::<SigningKey>(pool)
+            .map_err(|_| String::from("No active signing key with that kid"))
+    }
+
+    fn load_into_key_ring(pool: &PgConnection, refresh_interval: Duration) -> Result<(), String> {
+        let keys = SigningKey::active(pool)?
+            .into_iter()
+            .map(|key| RingKey {
+                kid: key.kid,
+                secret: key.secret,
+                created_at: key.created_at
+            })
+            .collect();
+
+        set_signing_keys(keys, refresh_interval);
+        Ok(())
+    }
+}
+
+// Loads the keys before the server starts and then reloads them every
+// `signing_key_refresh_seconds`, so rotations and retirements reach every replica. Fails
+// when the first load does, as the server couldn't check any keyed token without it
+pub fn spawn_refresh(pool: PgPool) -> Result<(), String> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    let refresh_seconds = config.signing_key_refresh_seconds.max(1);
+    let refresh_interval = Duration::seconds(refresh_seconds as i64);
+
+    let connection = pool.get()
+        .map_err(|error| format!("Could not connect to PG to load signing keys: {}", error))?;
+    SigningKey::load_into_key_ring(&connection, refresh_interval)?;
+    drop(connection);
+
+    thread::Builder::new()
+        .name(String::from("signing-key-refresh"))
+        .spawn(move || loop {
+            thread::sleep(time::Duration::from_secs(refresh_seconds));
+
+            let loaded = pool
+                .get()
+                .map_err(|error| error.to_string())
+                .and_then(|connection| SigningKey::load_into_key_ring(&connection, refresh_interval));
+
+            // The keys from the last refresh stay in use until the next one works
+            if let Err(error) = loaded {
+                log::error!("Could not refresh signing keys: {}", error);
+            }
+        })
+        .map(|_handle| ())
+        .map_err(|error| format!("Could not start signing key refresh thread: {}", error))
+}
@@ -1,18 +1,21 @@
 extern crate chrono;
 
-use chrono::{Duration, Utc};
-use std::convert::TryFrom;
+use chrono::{Duration, NaiveDateTime, Utc};
 
-use diesel::{PgConnection, RunQueryDsl};
+use diesel::{Connection, PgConnection, RunQueryDsl};
 use serde::{Serialize, Deserialize};
 
-use crate::modules::jwt::{jwt_factory, Claims, validate_token};
+use crate::modules::jwt::{jwt_factory, expires_in, Amr, Claims, TokenKind};
 
 use crate::schema::users;
-use crate::models;
+use crate::models::password_history::PasswordHistory;
+use crate::config::Config;
+use crate::errors::errors::AppError;
 use crate::modules::hash::{hash_password, verify_password};
+use crate::modules::lockout::LockoutPolicy;
+use crate::modules::password_policy::{validate_password, FieldError};
 
-#[derive(Debug, Queryable, Insertable, Serialize, Deserialize)]
+#[derive(Debug, Clone, Queryable, Insertable, Serialize, Deserialize)]
 #[table_name="users"]
 pub struct User {
     pub id: i32,
@@ -21,7 +24,28 @@ pub struct User {
     #[serde(skip)]
     pub password: String,
     #[serde(skip)]
-    pub refresh_token: Option<String>
+    pub refresh_token: Option<String>,
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    #[serde(skip)]
+    pub totp_enabled: bool,
+    pub email_verified: bool,
+    pub locale: String,
+    // E.164, e.g. "+34600000000", used to text login codes
+    pub phone: Option<String>,
+    #[serde(skip)]
+    pub failed_login_attempts: i32,
+    #[serde(skip)]
+    pub locked_until: Option<NaiveDateTime>,
+    #[serde(skip)]
+    pub is_admin: bool,
+    #[serde(skip_serializing)]
+    pub password_changed_at: NaiveDateTime,
+    // The TOTP time step of the last accepted code, codes from it or earlier can't be replayed
+    #[serde(skip)]
+    pub totp_last_step: Option<i64>,
+    // Set once a code texted to `phone` comes back, only then can it be used to log in
+    pub phone_verified: bool
 }
 
 #[derive(Debug, Insertable, Serialize, Deserialize)]
@@ -30,6 +54,15 @@ pub struct NewUser {
     pub name: String,
     pub email: String,
     pub password: String,
+    // Picks the language of the emails we send, e.g. "en" or "es-MX"
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    #[serde(default)]
+    pub phone: Option<String>,
+}
+
+fn default_locale() -> String {
+    String::from("en")
 }
 
 #[derive(Debug, Deserialize)]
@@ -46,80 +79,211 @@ pub struct UserLoggedIn {
     pub refresh_token: Option<String>
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MfaChallenge {
+    pub challenge_token: String,
+    pub methods: Vec<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountLocked {
+    // Seconds until the next attempt will be considered
+    pub retry_after: i64
+}
+
+impl AccountLocked {
+    pub fn until(locked_until: NaiveDateTime) -> AccountLocked {
+        let remaining = locked_until - Utc::now().naive_utc();
+
+        AccountLocked { retry_after: remaining.num_seconds().max(1) }
+    }
+}
+
+// Only good for `/app/password/expired`, the user logs in as usual afterwards
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordExpired {
+    pub change_token: String
+}
+
+// A correct password only finishes the login when the user has no second factor
+// and their password hasn't expired
+#[derive(Debug, Clone)]
+pub enum LoginOutcome {
+    LoggedIn(UserLoggedIn),
+    MfaRequired(MfaChallenge),
+    Locked(AccountLocked),
+    PasswordExpired(PasswordExpired)
+}
+
+// How far a login got before any tokens are issued. `repository::finish_login` takes it
+// from there, so every way of logging in stores its session the same way
+#[derive(Debug)]
+pub enum Authenticated {
+    // Any second factor the user set up still has to be passed
+    FirstFactor(User, Amr),
+    AllFactors(User, Vec<Amr>),
+    // Locked or expired, there is nothing to issue tokens for
+    Stopped(LoginOutcome)
+}
+
 #[derive(Deserialize, Clone, Debug)]
 pub struct UserLogout {
     pub id: i32
 }
 
 impl User {
-    pub fn get_all(pool: &PgConnection) -> Vec<User> {
+    pub fn get_all(pool: &PgConnection) -> Result<Vec<User>, AppError> {
         use crate::schema::users::dsl::*;
 
         users
             .load::<User>(pool)
-            .expect("Could not query PG for all users")
+            .map_err(|error| AppError::Internal(format!("Could not query PG for all users: {}", error)))
     }
 
-    pub fn get(pool: &PgConnection, user_id: i32) -> User {
-        use crate::schema::users::dsl::*;
+    pub fn find(pool: &PgConnection, user_id: i32) -> Result<User, AppError> {
+        use crate::schema::users::dsl::users;
         use crate::diesel::QueryDsl;
-        use crate::schema::users::columns::id;
-        use crate::diesel::ExpressionMethods;
 
         users
-            .filter(id.eq(user_id))
-            .first(pool)
-            .expect("Could not query PG for user by ID")
+            .find(user_id)
+            .get_result::<User>(pool)
+            .map_err(|error| match error {
+                diesel::result::Error::NotFound => AppError::user_not_found(),
+                error => AppError::Internal(format!("Could not query PG for user {}: {}", user_id, error))
+            })
     }
 
-    pub fn create(pool: &PgConnection, user: actix_web::web::Json<models::user::NewUser>) -> Result<NewUser, String> {
-        use crate::schema::users::dsl::*;
-        use crate::schema::users::dsl::{email};
+    // Returns when the account is locked until, if this failure locked it
+    pub fn record_failed_login(pool: &PgConnection, user_id: i32) -> Result<Option<NaiveDateTime>, String> {
+        use crate::schema::users::dsl::{users, id, failed_login_attempts, locked_until};
         use crate::diesel::QueryDsl;
         use crate::diesel::ExpressionMethods;
 
-        let user_already_exists = users
-            .filter(email.eq(&user.email))
-            .load::<User>(pool)
-            .unwrap();
-
-        if user_already_exists.is_empty() {
-            
-            let user_password = hash_password(user.password
-                .to_string())
-                .expect("Could not hash password");
-
-            let new_user = NewUser {
-                name: user.name.to_string(),
-                email: user.email.to_string(),
-                password: user_password
-            };
-            
-            diesel::insert_into(users)
-                .values(&new_user)
+        // Incremented in the database so concurrent guesses all count
+        let updated_user = diesel::update(users.filter(id.eq(user_id)))
+            .set(failed_login_attempts.eq(failed_login_attempts + 1))
+            .get_result::<User>(pool)
+            .map_err(|error| format!("Could not record failed login: {}", error))?;
+
+        let lockout = LockoutPolicy::from_config()
+            .lockout_for(updated_user.failed_login_attempts)
+            .map(|lockout| Utc::now().naive_utc() + lockout);
+
+        if lockout.is_some() {
+            diesel::update(users.filter(id.eq(user_id)))
+                .set(locked_until.eq(lockout))
                 .execute(pool)
-                .expect("Could not create new user");
+                .map_err(|error| format!("Could not lock account: {}", error))?;
+        }
+
+        Ok(lockout)
+    }
 
-            return Ok(new_user);
+    pub fn unlock(pool: &PgConnection, user_id: i32) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, failed_login_attempts, locked_until};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let unlocked = diesel::update(users.filter(id.eq(user_id)))
+            .set((failed_login_attempts.eq(0), locked_until.eq(None::<NaiveDateTime>)))
+            .execute(pool)
+            .map_err(|error| format!("Could not unlock account: {}", error))?;
+
+        match unlocked {
+            1 => Ok(()),
+            _ => Err(AppError::user_not_found())
         }
+    }
+
+    // For accounts created by operators, who vouch for the email address
+    pub fn grant_admin(pool: &PgConnection, user_email: &str) -> Result<User, String> {
+        use crate::schema::users::dsl::{users, email, is_admin, email_verified};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
 
-        Err(String::from("Email already in use"))
+        diesel::update(users.filter(email.eq(user_email)))
+            .set((is_admin.eq(true), email_verified.eq(true)))
+            .get_result::<User>(pool)
+            .map_err(|_| String::from("User does not exist"))
     }
 
-    pub fn login(pool: &PgConnection, user: actix_web::web::Json<models::user::UserLogin>) -> Result<UserLoggedIn, String> {
-        use crate::schema::users::dsl::*;
-        use crate::schema::users::dsl::{name};
+    // Refresh tokens are the only long lived credential, access tokens run out on their own
+    pub fn revoke_sessions(pool: &PgConnection, user_id: i32) -> Result<User, diesel::result::Error> {
+        User::logout(pool, UserLogout { id: user_id })
+    }
+
+    // Every way of setting a new password ends up here, so the policy and the
+    // password history are always enforced
+    pub fn update_password(pool: &PgConnection, user_id: i32, new_password: String) -> Result<(), AppError> {
+        use crate::schema::users::dsl::{users, id, password, password_changed_at};
         use crate::diesel::QueryDsl;
         use crate::diesel::ExpressionMethods;
 
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
         let existing_user = users
-            .filter(name.eq(&user.name))
-            .get_result::<User>(pool);
-        
-        match existing_user {
-           Ok(registered_user) => User::handle_login(registered_user, user, pool),
-           Err(_error) => Err(String::from("User does not exist"))
+            .find(user_id)
+            .get_result::<User>(pool)
+            .map_err(|_| AppError::user_not_found())?;
+
+        validate_password(&new_password, &[&existing_user.name, &existing_user.email])?;
+
+        let history_size = config.password_history_size;
+
+        if history_size > 0 {
+            let mut previous_hashes = vec![existing_user.password.clone()];
+            previous_hashes.extend(PasswordHistory::recent(pool, user_id, history_size - 1)?);
+
+            let is_reused = previous_hashes
+                .into_iter()
+                .any(|previous_hash| verify_password(previous_hash, new_password.clone()).unwrap_or(false));
+
+            if is_reused {
+                return Err(vec![FieldError::password(
+                    "reused",
+                    format!("Password must not be one of your last {} passwords", history_size)
+                )].into());
+            }
         }
+
+        let new_password_hash = hash_password(new_password)
+            .map_err(|_| String::from("Could not hash password"))?;
+
+        pool.transaction::<_, diesel::result::Error, _>(|| {
+            if history_size > 0 {
+                PasswordHistory::record(pool, user_id, existing_user.password.clone(), history_size - 1)?;
+            }
+
+            diesel::update(users.filter(id.eq(user_id)))
+                .set((password.eq(new_password_hash), password_changed_at.eq(Utc::now().naive_utc())))
+                .execute(pool)
+        })
+        .map_err(|error| format!("Could not update password: {}", error))?;
+
+        Ok(())
+    }
+
+    // Replaces the hash of an unchanged password, e.g. after the hashing parameters were raised
+    pub fn set_password_hash(pool: &PgConnection, user_id: i32, password_hash: String) -> Result<(), String> {
+        use crate::schema::users::dsl::{users, id, password};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(password.eq(password_hash))
+            .execute(pool)
+            .map_err(|error| format!("Could not rehash password: {}", error))?;
+
+        Ok(())
+    }
+
+    pub fn password_has_expired(existing_user: &User) -> bool {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        config.password_max_age_days > 0
+            && existing_user.password_changed_at + Duration::days(config.password_max_age_days) < Utc::now().naive_utc()
     }
 
     pub fn logout(pool: &PgConnection, user: UserLogout) -> Result<User, diesel::result::Error> {
@@ -145,152 +309,45 @@ impl User {
 
 }
 
-pub trait UserManager {
-    fn handle_login(existing_user: User, user: actix_web::web::Json<models::user::UserLogin>, pool: &PgConnection) -> Result<UserLoggedIn, String>;
-    fn validate_refresh_token(pool: &PgConnection, refresh_token: String, user_id: &i32) -> Result<bool, String>;
-    fn reauth(pool: &PgConnection, user_id: &i32) -> Result<NewTokens, String>;
-}
-
 #[derive(Debug, Clone)]
 pub struct NewTokens {
     pub refresh_token: String,
     pub access_token: String
 }
 
-impl UserManager for User {
-
-    fn reauth(pool: &PgConnection, user_id: &i32) -> Result<NewTokens, String> {
-        use crate::schema::users::dsl::{users, id, refresh_token};
-        use crate::diesel::QueryDsl;
-        use crate::diesel::ExpressionMethods;
-        
-        let one_week_from_now = Utc::now() + Duration::days(7);
-
-        let refresh_timestamp = usize::try_from(one_week_from_now.timestamp())
-            .unwrap();        
-
-        let refresh_token_claims = Claims {
-            exp: refresh_timestamp,
-        };
-
-        let refresh_jwt = jwt_factory(refresh_token_claims);
-
-        let user_with_refresh_token: Result<User, diesel::result::Error> = diesel::update(
-                users.filter(id.eq(user_id))
-            )
-            .set(refresh_token.eq(refresh_jwt.clone()))
-            .get_result(pool);
-
-        match user_with_refresh_token {
-            Ok(updated_user) => {
-                
-                let fifteen_min_from_now = Utc::now() + Duration::minutes(15);
-
-                let access_timestamp = usize::try_from(fifteen_min_from_now.timestamp())
-                    .unwrap();
-
-                let access_token_claims = Claims {
-                    exp: access_timestamp
-                };
-
-                let new_access_token = jwt_factory(access_token_claims);
-
-                Ok(NewTokens {
-                    refresh_token: updated_user.refresh_token.unwrap(),
-                    access_token: new_access_token
-                })
-            },
-            Err(error) => Err(String::from(format!("Could not reauth user: {}", error)))
-        }
+// The refresh token only works once it's stored as the user's current one
+pub fn session_tokens(user_id: i32, amr: Vec<Amr>, auth_time: usize) -> NewTokens {
+    let refresh_token_claims = Claims {
+        sub: user_id,
+        exp: expires_in(Duration::days(7)),
+        kind: TokenKind::Refresh,
+        amr: amr.clone(),
+        auth_time
+    };
+
+    let access_token_claims = Claims {
+        sub: user_id,
+        exp: expires_in(Duration::minutes(15)),
+        kind: TokenKind::Access,
+        amr,
+        auth_time
+    };
+
+    NewTokens {
+        refresh_token: jwt_factory(refresh_token_claims),
+        access_token: jwt_factory(access_token_claims)
     }
+}
 
-    fn validate_refresh_token(pool: &PgConnection, refresh_token: String, user_id: &i32) -> Result<bool, String> {
-        use crate::schema::users::dsl::{users, id};
-        use crate::diesel::QueryDsl;
-        use crate::diesel::ExpressionMethods;
-
-        let user = users
-            .filter(id.eq(&user_id))
-            .get_result::<User>(pool);
-
-        match user {
-            Ok(selected_user) => {
-                let user_refresh_token = selected_user.refresh_token.unwrap();
+// Every way of logging in issues tokens through `repository::issue_tokens`, so this
+// covers passwordless logins too
+pub fn ensure_email_verified(existing_user: &User) -> Result<(), AppError> {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
 
-                match user_refresh_token.eq(&refresh_token) {
-                    true => Ok(validate_token(&user_refresh_token)),
-                    false => Err(String::from("Failed to reauth: refresh tokens do not match."))
-                }
-            },
-            Err(error) => Err(String::from(format!("Could not find a user with that ID: {}", error)))
-        }
+    if config.require_verified_email && !existing_user.email_verified {
+        return Err(AppError::Forbidden("email_not_verified", String::from("Email address has not been verified")));
     }
 
-    fn handle_login(existing_user: User, user: actix_web::web::Json<models::user::UserLogin>, pool: &PgConnection) -> Result<UserLoggedIn, String> {
-
-        use crate::schema::users::dsl::*;
-        use crate::schema::users::dsl::{id, refresh_token};
-        use crate::diesel::QueryDsl;
-        use crate::diesel::ExpressionMethods;
-
-        let password_is_valid = verify_password(
-            existing_user.password, 
-            user.password.to_string()
-        );
-
-        let one_week_from_now = Utc::now() + Duration::days(7);
-
-        let timestamp = usize::try_from(one_week_from_now.timestamp())
-            .unwrap();        
-
-        let refresh_token_claims = Claims {
-            exp: timestamp,
-        };
-
-        let refresh_jwt = jwt_factory(refresh_token_claims);
-
-        let user_with_refresh_token: Result<User, diesel::result::Error> = diesel::update(
-                users.filter(id.eq(existing_user.id))
-            )
-            .set(refresh_token.eq(refresh_jwt.clone()))
-            .get_result(pool);
-
-        match user_with_refresh_token {
-            Ok(user_with_session) => {
-                match password_is_valid {
-                    Ok(result) => {
-                        match result {
-                            true => {
-                                let fifteen_min_from_now = Utc::now() + Duration::minutes(15);
-
-                                let timestamp = usize::try_from(fifteen_min_from_now.timestamp())
-                                    .unwrap();
-
-                                let access_token_claims = Claims {
-                                    exp: timestamp
-                                };
-
-                                let logged_in_user = UserLoggedIn {
-                                    email: user_with_session.email,
-                                    jwt: jwt_factory(access_token_claims),
-                                    name: user_with_session.name,
-                                    refresh_token: user_with_session.refresh_token
-                                };
-                                Ok(logged_in_user)
-                            },
-                            false => {
-                                Err(String::from("Incorrect password"))
-                            }
-                        }
-                    },
-                    Err(_) => {
-                        Err(String::from("Could not verify password"))
-                    }
-                }        
-            },
-            Err(_error) => {
-                Err(String::from("Could not create refresh token"))
-            }
-        }
-    }
-}
\ No newline at end of file
+    Ok(())
+}
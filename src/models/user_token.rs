@@ -0,0 +1,147 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::errors::errors::AppError;
+use crate::schema::user_tokens;
+use crate::modules::hash::hash_token;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    MagicLink,
+}
+
+impl TokenPurpose {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenPurpose::EmailVerification => "email_verification",
+            TokenPurpose::PasswordReset => "password_reset",
+            TokenPurpose::MagicLink => "magic_link",
+        }
+    }
+}
+
+#[derive(Debug, Queryable)]
+pub struct UserToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="user_tokens"]
+pub struct NewUserToken {
+    pub user_id: i32,
+    pub purpose: String,
+    pub token_hash: String,
+    pub expires_at: NaiveDateTime
+}
+
+fn generate_token() -> Result<String, String> {
+    let mut token = [0u8; 32];
+
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| String::from("Could not generate token"))?;
+
+    Ok(base64::encode_config(token, base64::URL_SAFE_NO_PAD))
+}
+
+impl UserToken {
+    // Returns the plaintext token to send to the user, any earlier unused token
+    // for the same purpose stops working
+    pub fn issue(pool: &PgConnection, for_user_id: i32, token_purpose: TokenPurpose, ttl: Duration) -> Result<String, String> {
+        use crate::schema::user_tokens::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let token = generate_token()?;
+        let now = Utc::now().naive_utc();
+
+        diesel::update(
+                user_tokens
+                    .filter(user_id.eq(for_user_id))
+                    .filter(purpose.eq(token_purpose.as_str()))
+                    .filter(used_at.is_null())
+            )
+            .set(used_at.eq(now))
+            .execute(pool)
+            .map_err(|error| format!("Could not revoke previous tokens: {}", error))?;
+
+        let new_token = NewUserToken {
+            user_id: for_user_id,
+            purpose: token_purpose.as_str().to_string(),
+            token_hash: hash_token(&token),
+            expires_at: now + ttl
+        };
+
+        diesel::insert_into(user_tokens)
+            .values(&new_token)
+            .execute(pool)
+            .map_err(|error| format!("Could not store token: {}", error))?;
+
+        Ok(token)
+    }
+
+    // Marks the token used and returns who it was issued to, in one statement so
+    // the same token can't be consumed twice
+    pub fn consume(pool: &PgConnection, token: &str, token_purpose: TokenPurpose) -> Result<i32, AppError> {
+        use crate::schema::user_tokens::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let now = Utc::now().naive_utc();
+
+        diesel::update(
+                user_tokens
+                    .filter(token_hash.eq(hash_token(token)))
+                    .filter(purpose.eq(token_purpose.as_str()))
+                    .filter(used_at.is_null())
+                    .filter(expires_at.gt(now))
+            )
+            .set(used_at.eq(now))
+            .get_result::<UserToken>(pool)
+            .map(|consumed_token| consumed_token.user_id)
+            .map_err(|_| AppError::invalid_token())
+    }
+
+    // Who a still usable token was issued to, without using it up
+    pub fn peek(pool: &PgConnection, token: &str, token_purpose: TokenPurpose) -> Result<i32, AppError> {
+        use crate::schema::user_tokens::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        user_tokens
+            .filter(token_hash.eq(hash_token(token)))
+            .filter(purpose.eq(token_purpose.as_str()))
+            .filter(used_at.is_null())
+            .filter(expires_at.gt(Utc::now().naive_utc()))
+            .select(user_id)
+            .first::<i32>(pool)
+            .map_err(|_| AppError::invalid_token())
+    }
+
+    pub fn last_issued_at(pool: &PgConnection, for_user_id: i32, token_purpose: TokenPurpose) -> Result<Option<NaiveDateTime>, String> {
+        use crate::schema::user_tokens::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        user_tokens
+            .filter(user_id.eq(for_user_id))
+            .filter(purpose.eq(token_purpose.as_str()))
+            .select(created_at)
+            .order(created_at.desc())
+            .first::<NaiveDateTime>(pool)
+            .map(Some)
+            .or_else(|error| match error {
+                diesel::result::Error::NotFound => Ok(None),
+                error => Err(format!("Could not load tokens: {}", error))
+            })
+    }
+}
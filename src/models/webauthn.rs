@@ -0,0 +1,429 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use diesel::{PgConnection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::errors::errors::AppError;
+use crate::schema::{webauthn_challenges, webauthn_credentials};
+use crate::models::password::PasswordManager;
+use crate::models::user::{User, Authenticated, LoginOutcome, AccountLocked};
+use crate::modules::hash::hash_token;
+use crate::modules::jwt::{jwt_factory, decode_claims, decode_token, expires_in, Amr, TokenKind};
+use crate::modules::webauthn::{
+    self, AuthenticatorSelection, CreationOptions, CredentialDescriptor, CredentialParameters,
+    Expected, RelyingParty, RequestOptions, UserEntity, ES256
+};
+
+const CEREMONY_TIMEOUT_MINUTES: i64 = 5;
+
+#[derive(Debug, Queryable)]
+pub struct WebauthnCredential {
+    pub id: i32,
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub transports: Vec<String>,
+    pub created_at: NaiveDateTime
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="webauthn_credentials"]
+pub struct NewWebauthnCredential {
+    pub user_id: i32,
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: i64,
+    pub transports: Vec<String>
+}
+
+#[derive(Debug, Insertable)]
+#[table_name="webauthn_challenges"]
+pub struct NewWebauthnChallenge {
+    pub challenge_hash: String,
+    pub expires_at: NaiveDateTime
+}
+
+// Handed to the browser alongside the options, the challenge in it is also stored so
+// it can only be answered once
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CeremonyClaims {
+    pub sub: Option<i32>,
+    pub exp: usize,
+    pub kind: TokenKind,
+    pub challenge: String,
+    // What the user already proved when the ceremony is the second step of a
+    // login, empty for passwordless logins
+    pub first_factors: Vec<Amr>
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RegistrationOptions {
+    pub ceremony_token: String,
+    pub public_key: CreationOptions
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AuthenticationOptions {
+    pub ceremony_token: String,
+    pub public_key: RequestOptions
+}
+
+// `name` starts a username-first passwordless login, `challenge_token` (from
+// `/app/login`) uses a passkey as the second factor, neither means any
+// discoverable credential on the device can be used
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationOptionsRequest {
+    pub name: Option<String>,
+    pub challenge_token: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebauthnRegistration {
+    pub ceremony_token: String,
+    pub credential: RegistrationCredential
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebauthnAuthentication {
+    pub ceremony_token: String,
+    pub credential: AuthenticationCredential
+}
+
+impl WebauthnCredential {
+    pub fn for_user(pool: &PgConnection, for_user_id: i32) -> Result<Vec<WebauthnCredential>, String> {
+        use crate::schema::webauthn_credentials::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        webauthn_credentials
+            .filter(user_id.eq(for_user_id))
+            .load::<WebauthnCredential>(pool)
+            .map_err(|error| format!("Could not load WebAuthn credentials: {}", error))
+    }
+
+    pub fn descriptor(&self) -> CredentialDescriptor {
+        CredentialDescriptor {
+            credential_type: String::from("public-key"),
+            id: self.credential_id.clone(),
+            transports: self.transports.clone()
+        }
+    }
+}
+
+fn credential_descriptors(pool: &PgConnection, user_id: i32) -> Result<Vec<CredentialDescriptor>, String> {
+    Ok(WebauthnCredential::for_user(pool, user_id)?
+        .iter()
+        .map(WebauthnCredential::descriptor)
+        .collect())
+}
+
+fn ceremony_token(sub: Option<i32>, kind: TokenKind, challenge: &str, first_factors: Vec<Amr>) -> String {
+    jwt_factory(CeremonyClaims {
+        sub,
+        exp: expires_in(Duration::minutes(CEREMONY_TIMEOUT_MINUTES)),
+        kind,
+        challenge: challenge.to_string(),
+        first_factors
+    })
+}
+
+fn store_challenge(pool: &PgConnection, challenge: &str) -> Result<(), AppError> {
+    use crate::schema::webauthn_challenges::dsl::{webauthn_challenges, expires_at};
+    use crate::diesel::QueryDsl;
+    use crate::diesel::ExpressionMethods;
+
+    let now = Utc::now().naive_utc();
+
+    // Whoever starts a ceremony cleans up the ones nobody finished
+    diesel::delete(webauthn_challenges.filter(expires_at.lt(now)))
+        .execute(pool)
+        .map_err(|error| format!("Could not delete expired WebAuthn challenges: {}", error))?;
+
+    diesel::insert_into(webauthn_challenges)
+        .values(&NewWebauthnChallenge {
+            challenge_hash: hash_token(challenge),
+            expires_at: now + Duration::minutes(CEREMONY_TIMEOUT_MINUTES)
+        })
+        .execute(pool)
+        .map_err(|error| format!("Could not store WebAuthn challenge: {}", error))?;
+
+    Ok(())
+}
+
+// Used up before the response is checked, so each challenge gets exactly one attempt
+fn consume_challenge(pool: &PgConnection, challenge: &str) -> Result<(), AppError> {
+    use crate::schema::webauthn_challenges::dsl::{webauthn_challenges, challenge_hash, expires_at};
+    use crate::diesel::QueryDsl;
+    use crate::diesel::ExpressionMethods;
+
+    let consumed = diesel::delete(
+            webauthn_challenges
+                .filter(challenge_hash.eq(hash_token(challenge)))
+                .filter(expires_at.gt(Utc::now().naive_utc()))
+        )
+        .execute(pool)
+        .map_err(|error| format!("Could not consume WebAuthn challenge: {}", error))?;
+
+    match consumed {
+        1 => Ok(()),
+        _ => Err(AppError::invalid_token())
+    }
+}
+
+fn decode_ceremony(token: &str, kind: TokenKind) -> Result<CeremonyClaims, AppError> {
+    let claims = decode_claims::<CeremonyClaims>(token)
+        .map_err(|_| AppError::invalid_token())?;
+
+    match claims.kind == kind {
+        true => Ok(claims),
+        false => Err(AppError::invalid_token())
+    }
+}
+
+// Whatever the browser or authenticator sent didn't check out
+fn invalid_ceremony(error: String) -> AppError {
+    AppError::BadRequest("webauthn_invalid", error)
+}
+
+pub trait WebauthnManager {
+    fn webauthn_registration_options(pool: &PgConnection, user_id: i32) -> Result<RegistrationOptions, AppError>;
+    fn finish_webauthn_registration(pool: &PgConnection, user_id: i32, registration: WebauthnRegistration) -> Result<bool, AppError>;
+    fn webauthn_authentication_options(pool: &PgConnection, request: AuthenticationOptionsRequest) -> Result<AuthenticationOptions, AppError>;
+    fn finish_webauthn_authentication(pool: &PgConnection, authentication: WebauthnAuthentication) -> Result<Authenticated, AppError>;
+}
+
+impl WebauthnManager for User {
+
+    fn webauthn_registration_options(pool: &PgConnection, user_id: i32) -> Result<RegistrationOptions, AppError> {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+        let existing_user = User::find(pool, user_id)?;
+        let challenge = webauthn::generate_challenge()?;
+        store_challenge(pool, &challenge)?;
+
+        let exclude_credentials = credential_descriptors(pool, user_id)?;
+
+        Ok(RegistrationOptions {
+            ceremony_token: ceremony_token(Some(user_id), TokenKind::WebauthnRegistration, &challenge, vec![]),
+            public_key: CreationOptions {
+                challenge,
+                rp: RelyingParty {
+                    id: config.webauthn_rp_id,
+                    name: config.webauthn_rp_name
+                },
+                user: UserEntity {
+                    id: webauthn::encode(&user_id.to_be_bytes()),
+                    name: existing_user.email,
+                    display_name: existing_user.name
+                },
+                pub_key_cred_params: vec![CredentialParameters {
+                    credential_type: String::from("public-key"),
+                    alg: ES256
+                }],
+                timeout: (CEREMONY_TIMEOUT_MINUTES * 60 * 1000) as u32,
+                attestation: String::from("none"),
+                exclude_credentials,
+                authenticator_selection: AuthenticatorSelection {
+                    resident_key: String::from("preferred"),
+                    user_verification: String::from("preferred")
+                }
+            }
+        })
+    }
+
+    fn finish_webauthn_registration(pool: &PgConnection, user_id: i32, registration: WebauthnRegistration) -> Result<bool, AppError> {
+        use crate::schema::webauthn_credentials::dsl::webauthn_credentials;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+        let ceremony = decode_ceremony(&registration.ceremony_token, TokenKind::WebauthnRegistration)?;
+
+        if ceremony.sub != Some(user_id) {
+            return Err(AppError::invalid_token());
+        }
+
+        consume_challenge(pool, &ceremony.challenge)?;
+
+        let response = registration.credential.response;
+
+        let credential = webauthn::verify_registration(
+            &webauthn::decode(&response.client_data_json).map_err(invalid_ceremony)?,
+            &webauthn::decode(&response.attestation_object).map_err(invalid_ceremony)?,
+            &Expected {
+                challenge: &ceremony.challenge,
+                origin: &config.webauthn_origin,
+                rp_id: &config.webauthn_rp_id,
+                user_verification: false
+            }
+        ).map_err(invalid_ceremony)?;
+
+        if registration.credential.id != webauthn::encode(&credential.credential_id) {
+            return Err(invalid_ceremony(String::from("Credential ID does not match the authenticator data")));
+        }
+
+        let new_credential = NewWebauthnCredential {
+            user_id,
+            credential_id: webauthn::encode(&credential.credential_id),
+            public_key: credential.public_key,
+            sign_count: i64::from(credential.sign_count),
+            transports: response.transports
+        };
+
+        diesel::insert_into(webauthn_credentials)
+            .values(&new_credential)
+            .execute(pool)
+            .map_err(|_| AppError::Conflict("credential_already_registered", String::from("Could not store credential, it may already be registered")))?;
+
+        Ok(true)
+    }
+
+    fn webauthn_authentication_options(pool: &PgConnection, request: AuthenticationOptionsRequest) -> Result<AuthenticationOptions, AppError> {
+        use crate::schema::users::dsl::{users, name};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+        let challenge = webauthn::generate_challenge()?;
+        store_challenge(pool, &challenge)?;
+
+        let (user_id, first_factors, allow_credentials) = match (request.challenge_token, request.name) {
+            (Some(challenge_token), _) => {
+                let mfa_challenge = decode_token(&challenge_token, TokenKind::MfaChallenge)
+                    .map_err(|_| AppError::invalid_token())?;
+                let allow_credentials = credential_descriptors(pool, mfa_challenge.sub)?;
+
+                (Some(mfa_challenge.sub), mfa_challenge.amr, allow_credentials)
+            },
+            // The name only picks which credentials to offer, any passkey that verifies its
+            // user can log in. Names without credentials, including unknown ones, get a
+            // made up one so the response doesn't say whether the account exists
+            (None, Some(user_name)) => {
+                let existing_user = users
+                    .filter(name.eq(&user_name))
+                    .get_result::<User>(pool)
+                    .ok();
+
+                let mut allow_credentials = match existing_user {
+                    Some(existing_user) => credential_descriptors(pool, existing_user.id)?,
+                    None => vec![]
+                };
+
+                if allow_credentials.is_empty() {
+                    allow_credentials.push(CredentialDescriptor {
+                        credential_type: String::from("public-key"),
+                        id: webauthn::decoy_credential_id(&config.hash_secret_key, &user_name)?,
+                        transports: vec![]
+                    });
+                }
+
+                (None, vec![], allow_credentials)
+            },
+            (None, None) => (None, vec![], vec![])
+        };
+
+        let user_verification = String::from(if first_factors.is_empty() { "required" } else { "preferred" });
+
+        Ok(AuthenticationOptions {
+            ceremony_token: ceremony_token(user_id, TokenKind::WebauthnAuthentication, &challenge, first_factors),
+            public_key: RequestOptions {
+                challenge,
+                rp_id: config.webauthn_rp_id,
+                timeout: (CEREMONY_TIMEOUT_MINUTES * 60 * 1000) as u32,
+                allow_credentials,
+                user_verification
+            }
+        })
+    }
+
+    fn finish_webauthn_authentication(pool: &PgConnection, authentication: WebauthnAuthentication) -> Result<Authenticated, AppError> {
+        use crate::schema::webauthn_credentials::dsl::{webauthn_credentials, id, credential_id, sign_count};
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+        let ceremony = decode_ceremony(&authentication.ceremony_token, TokenKind::WebauthnAuthentication)?;
+        consume_challenge(pool, &ceremony.challenge)?;
+
+        let stored_credential = webauthn_credentials
+            .filter(credential_id.eq(&authentication.credential.id))
+            .get_result::<WebauthnCredential>(pool)
+            .map_err(|_| AppError::Unauthorized("unknown_credential", String::from("Unknown credential")))?;
+
+        if ceremony.sub.is_some() && ceremony.sub != Some(stored_credential.user_id) {
+            return Err(AppError::Unauthorized("credential_user_mismatch", String::from("Credential belongs to another user")));
+        }
+
+        let existing_user = User::find(pool, stored_credential.user_id)?;
+
+        // Like a password, no passkey is checked while the account is locked
+        if let Some(until) = existing_user.locked_until.filter(|until| *until > Utc::now().naive_utc()) {
+            return Ok(Authenticated::Stopped(LoginOutcome::Locked(AccountLocked::until(until))));
+        }
+
+        let response = authentication.credential.response;
+
+        // Without a password the passkey has to be a second factor on its own, so the
+        // authenticator must have verified the user (PIN, biometrics) and not just their presence
+        let new_sign_count = webauthn::verify_authentication(
+            &webauthn::decode(&response.client_data_json).map_err(invalid_ceremony)?,
+            &webauthn::decode(&response.authenticator_data).map_err(invalid_ceremony)?,
+            &webauthn::decode(&response.signature).map_err(invalid_ceremony)?,
+            &stored_credential.public_key,
+            stored_credential.sign_count as u32,
+            &Expected {
+                challenge: &ceremony.challenge,
+                origin: &config.webauthn_origin,
+                rp_id: &config.webauthn_rp_id,
+                user_verification: ceremony.first_factors.is_empty()
+            }
+        ).map_err(invalid_ceremony)?;
+
+        diesel::update(webauthn_credentials.filter(id.eq(stored_credential.id)))
+            .set(sign_count.eq(i64::from(new_sign_count)))
+            .execute(pool)
+            .map_err(|error| format!("Could not update credential: {}", error))?;
+
+        // A passwordless login never went through `/app/login`, where expired passwords are caught
+        if ceremony.first_factors.is_empty() && User::password_has_expired(&existing_user) {
+            return Ok(Authenticated::Stopped(LoginOutcome::PasswordExpired(User::password_change_token(&existing_user))));
+        }
+
+        let mut amr = ceremony.first_factors;
+        amr.push(Amr::Webauthn);
+
+        Ok(Authenticated::AllFactors(existing_user, amr))
+    }
+}
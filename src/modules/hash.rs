@@ -1,41 +1,336 @@
+use std::fmt;
+use std::str::FromStr;
+
 use crate::config::Config;
+use crate::modules::legacy_hash;
 use argonautica::{Hasher, Verifier};
+use argonautica::config::Variant;
+use ring::digest::{digest, SHA256};
+
+#[derive(Debug)]
+pub enum HashError {
+    Argon2(argonautica::Error),
+    // A known format whose parameters or encoding don't parse
+    Malformed(String),
+    // Not a format we can verify at all
+    UnsupportedFormat,
+    // The stored value isn't a hash, so comparing against it would accept the stored value itself
+    Plaintext,
+    // The hash was peppered with a version that's no longer in `hash_secret_keys`
+    UnknownPepperVersion(String)
+}
+
+impl fmt::Display for HashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashError::Argon2(error) => write!(f, "Argon2 error: {}", error),
+            HashError::Malformed(error) => write!(f, "Malformed password hash: {}", error),
+            HashError::UnsupportedFormat => write!(f, "Unsupported password hash format"),
+            HashError::Plaintext => write!(f, "Stored password is not hashed"),
+            HashError::UnknownPepperVersion(version) => write!(f, "Unknown hash secret key version: {}", version)
+        }
+    }
+}
+
+impl From<argonautica::Error> for HashError {
+    fn from(error: argonautica::Error) -> HashError {
+        HashError::Argon2(error)
+    }
+}
+
+// The argon2 cost parameters, as configured or as read back from a stored hash
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashParams {
+    pub variant: String,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub lanes: u32
+}
+
+impl HashParams {
+    pub fn from_config() -> HashParams {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        HashParams {
+            variant: config.argon2_variant,
+            memory_kib: config.argon2_memory_kib,
+            iterations: config.argon2_iterations,
+            lanes: config.argon2_lanes
+        }
+    }
+
+    // Reads e.g. `$argon2id$v=19$m=4096,t=192,p=4$<salt>$<hash>`
+    pub fn parse(hash: &str) -> Option<HashParams> {
+        let mut sections = hash.split('$').skip(1);
+
+        let variant = sections.next()?.to_string();
+        let mut params = sections.find(|section| section.starts_with("m="))?.split(',');
+
+        let mut param = |name: &str| -> Option<u32> {
+            params.next()?
+                .strip_prefix(name)?
+                .parse()
+                .ok()
+        };
+
+        Some(HashParams {
+            variant,
+            memory_kib: param("m=")?,
+            iterations: param("t=")?,
+            lanes: param("p=")?
+        })
+    }
+
+    // Fewer lanes only means less parallelism, so only a weaker variant, memory or
+    // iteration count counts against a hash
+    pub fn is_weaker_than(&self, other: &HashParams) -> bool {
+        self.variant != other.variant
+            || self.memory_kib < other.memory_kib
+            || self.iterations < other.iterations
+    }
+}
+
+// The pepper for a version recorded in a hash, or the unversioned `hash_secret_key`
+fn pepper(config: &Config, version: Option<&str>) -> Result<String, HashError> {
+    match version {
+        Some(version) => config.hash_secret_keys
+            .get(version)
+            .cloned()
+            .ok_or_else(|| HashError::UnknownPepperVersion(version.to_string())),
+        None => Ok(config.hash_secret_key.clone())
+    }
+}
+
+// Splits the `keyid` recording the pepper version out of the hash's params, as
+// argonautica doesn't accept it: `$argon2id$v=19$m=4096,t=192,p=4,keyid=2$<salt>$<hash>`
+pub fn split_pepper_version(hash: &str) -> (Option<String>, String) {
+    let mut version = None;
+
+    let sections: Vec<String> = hash
+        .split('$')
+        .map(|section| {
+            if !section.starts_with("m=") {
+                return section.to_string();
+            }
 
-pub fn hash_password(password: String) -> Result<String, argonautica::Error> {
+            section
+                .split(',')
+                .filter(|param| match param.strip_prefix("keyid=") {
+                    Some(keyid) => {
+                        version = Some(keyid.to_string());
+                        false
+                    },
+                    None => true
+                })
+                .collect::<Vec<&str>>()
+                .join(",")
+        })
+        .collect();
+
+    (version, sections.join("$"))
+}
+
+fn with_pepper_version(hash: &str, version: &str) -> String {
+    hash
+        .split('$')
+        .map(|section| match section.starts_with("m=") {
+            true => format!("{},keyid={}", section, version),
+            false => section.to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("$")
+}
+
+// New hashes are always argon2, legacy formats are only ever verified
+pub fn hash_password(password: String) -> Result<String, HashError> {
     let config = Config::from_env()
         .expect("Must set env vars in config file");
 
+    let version = config.hash_secret_key_version.clone();
+    let secret_key = pepper(&config, version.as_deref())?;
+
+    let params = HashParams::from_config();
+    let variant = Variant::from_str(&params.variant)?;
+
     let mut hasher = Hasher::default();
 
     hasher
+        .configure_variant(variant)
+        .configure_memory_size(params.memory_kib)
+        .configure_iterations(params.iterations)
+        .configure_lanes(params.lanes)
+        .configure_threads(params.lanes)
         .with_password(password)
-        .with_secret_key(config.hash_secret_key)
-        .hash()
+        .with_secret_key(secret_key);
+
+    let hash = hasher.hash()?;
+
+    match version {
+        Some(version) => Ok(with_pepper_version(&hash, &version)),
+        None => Ok(hash)
+    }
 }
 
-pub fn verify_password(hash: String, password: String) -> Result<bool, argonautica::Error> {
+fn verify_argon2(hash: String, password: String) -> Result<bool, HashError> {
     let config = Config::from_env()
         .expect("Must set env vars in config file");
 
+    let (version, hash) = split_pepper_version(&hash);
+    let secret_key = pepper(&config, version.as_deref())?;
+
     let mut verifier = Verifier::default();
 
     verifier
         .with_hash(hash)
         .with_password(password)
-        .with_secret_key(config.hash_secret_key)
+        .with_secret_key(secret_key)
         .verify()
+        .map_err(HashError::from)
+}
+
+// Picks the algorithm from the hash's PHC or modular crypt prefix, so users imported
+// from older systems can log in with their existing passwords
+pub fn verify_password(hash: String, password: String) -> Result<bool, HashError> {
+    match hash.as_str() {
+        hash if hash.starts_with("$argon2") => verify_argon2(hash.to_string(), password),
+        hash if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") => {
+            legacy_hash::verify_bcrypt(hash, &password)
+        },
+        hash if hash.starts_with("$pbkdf2-sha256$") => legacy_hash::verify_pbkdf2_sha256(hash, &password),
+        hash if hash.starts_with("$scrypt$") => legacy_hash::verify_scrypt(hash, &password),
+        hash if !hash.starts_with('$') => Err(HashError::Plaintext),
+        _ => Err(HashError::UnsupportedFormat)
+    }
+}
+
+// For checking credentials, where a stored hash that can't be used at all has to look
+// like a wrong password, or the response would tell that the account exists
+pub fn password_matches(hash: String, password: String) -> bool {
+    verify_password(hash, password).unwrap_or_else(|error| {
+        log::error!("Could not verify password: {}", error);
+        false
+    })
+}
+
+lazy_static! {
+    // Made with the configured parameters, so checking against it costs the same as
+    // checking against a user's hash
+    static ref DUMMY_HASH: Option<String> = hash_password(String::from("no account has this password"))
+        .map_err(|error| log::error!("Could not make the dummy password hash: {}", error))
+        .ok();
+}
+
+// For logins without an account, does the argon2 work a wrong password would so the
+// response time doesn't tell that the name is unknown
+pub fn dummy_password_check(password: String) {
+    if let Some(hash) = DUMMY_HASH.as_ref() {
+        password_matches(hash.clone(), password);
+    }
+}
+
+// True when the hash was made with weaker parameters or another pepper than are
+// configured now, so it should be replaced the next time the password is known
+pub fn needs_rehash(hash: &str) -> bool {
+    let config = Config::from_env()
+        .expect("Must set env vars in config file");
+
+    let (version, _) = split_pepper_version(hash);
+
+    if version != config.hash_secret_key_version {
+        return true;
+    }
+
+    match HashParams::parse(hash) {
+        Some(params) => params.is_weaker_than(&HashParams::from_config()),
+        None => true
+    }
+}
+
+// Emailed tokens are long and random, so unlike passwords a fast hash is enough
+// and lets them be looked up directly by their hash
+pub fn hash_token(token: &str) -> String {
+    digest(&SHA256, token.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
 #[test]
 fn verification_succeeded() {
     let hash = hash_password(String::from("123")).unwrap();
     let hash_verification = verify_password(hash, String::from("123")).unwrap();
-    assert_eq!(hash_verification, true);
+    assert!(hash_verification);
 }
 
 #[test]
 fn verification_failed() {
     let hash = hash_password(String::from("123")).unwrap();
     let bad_hash_verification = verify_password(hash, String::from("xnpgu")).unwrap();
-    assert_eq!(bad_hash_verification, false);
-}
\ No newline at end of file
+    assert!(!bad_hash_verification);
+}
+
+#[test]
+fn unusable_hashes_never_match() {
+    assert!(!password_matches(String::from("keyboardcat"), String::from("keyboardcat")));
+    assert!(!password_matches(String::from("$md5$salt$hash"), String::from("keyboardcat")));
+}
+
+#[test]
+fn token_hash_is_stable_hex() {
+    assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    assert_ne!(hash_token("abc"), hash_token("abd"));
+}
+
+#[test]
+fn reads_params_from_a_hash() {
+    let params = HashParams::parse("$argon2id$v=19$m=4096,t=192,p=4$o2y5PU86Vt+sr93N7YUGgC7AMpTKpTQCk4tNGUPZMY4$yzP/ukZRPIbZg6PvgnUUobUMbApfF9RH6NagL9L4Xr4");
+
+    assert_eq!(params, Some(HashParams {
+        variant: String::from("argon2id"),
+        memory_kib: 4096,
+        iterations: 192,
+        lanes: 4
+    }));
+    assert_eq!(HashParams::parse("keyboardcat"), None);
+}
+
+#[test]
+fn weaker_params_need_rehash() {
+    let configured = HashParams { variant: String::from("argon2id"), memory_kib: 65536, iterations: 3, lanes: 4 };
+
+    let weaker_memory = HashParams { memory_kib: 4096, ..configured.clone() };
+    let older_variant = HashParams { variant: String::from("argon2i"), ..configured.clone() };
+    let fewer_lanes = HashParams { lanes: 1, ..configured.clone() };
+    let stronger = HashParams { memory_kib: 131072, iterations: 4, ..configured.clone() };
+
+    assert!(weaker_memory.is_weaker_than(&configured));
+    assert!(older_variant.is_weaker_than(&configured));
+    assert!(!fewer_lanes.is_weaker_than(&configured));
+    assert!(!stronger.is_weaker_than(&configured));
+}
+
+#[test]
+fn refuses_plaintext_and_unknown_formats() {
+    match verify_password(String::from("keyboardcat"), String::from("keyboardcat")) {
+        Err(HashError::Plaintext) => {},
+        other => panic!("Expected plaintext to be refused, got {:?}", other)
+    }
+
+    match verify_password(String::from("$md5$abc$def"), String::from("keyboardcat")) {
+        Err(HashError::UnsupportedFormat) => {},
+        other => panic!("Expected an unsupported format, got {:?}", other)
+    }
+}
+
+#[test]
+fn pepper_version_round_trips_through_the_hash() {
+    let hash = "$argon2id$v=19$m=4096,t=192,p=4$o2y5PU86Vt+sr93N7YUGgC7AMpTKpTQCk4tNGUPZMY4$yzP/ukZRPIbZg6PvgnUUobUMbApfF9RH6NagL9L4Xr4";
+    let versioned = with_pepper_version(hash, "2");
+
+    assert_eq!(versioned, "$argon2id$v=19$m=4096,t=192,p=4,keyid=2$o2y5PU86Vt+sr93N7YUGgC7AMpTKpTQCk4tNGUPZMY4$yzP/ukZRPIbZg6PvgnUUobUMbApfF9RH6NagL9L4Xr4");
+    assert_eq!(split_pepper_version(&versioned), (Some(String::from("2")), String::from(hash)));
+    assert_eq!(split_pepper_version(hash), (None, String::from(hash)));
+    assert_eq!(HashParams::parse(&versioned), HashParams::parse(hash));
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use futures::channel::oneshot;
+
+use crate::config::Config;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+#[derive(Debug, PartialEq)]
+pub enum HashPoolError {
+    // Every thread is busy and the queue is full
+    Saturated,
+    // The job panicked before sending its result
+    Canceled
+}
+
+impl fmt::Display for HashPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HashPoolError::Saturated => write!(f, "Password hashing is at capacity"),
+            HashPoolError::Canceled => write!(f, "Password hashing job failed")
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HashPoolStats {
+    pub threads: usize,
+    pub queue_size: usize,
+    pub queue_depth: usize,
+    pub active: usize,
+    pub rejected: usize
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicUsize,
+    active: AtomicUsize,
+    rejected: AtomicUsize
+}
+
+// A fixed set of threads for argon2, which takes long enough per call to stall an
+// actix worker. Jobs past `queue_size` are turned away instead of waiting
+pub struct HashPool {
+    sender: Mutex<SyncSender<Job>>,
+    counters: Arc<Counters>,
+    threads: usize,
+    queue_size: usize
+}
+
+impl HashPool {
+    pub fn new(threads: usize, queue_size: usize) -> HashPool {
+        let (sender, receiver) = sync_channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
+
+        for index in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            let counters = counters.clone();
+
+            thread::Builder::new()
+                .name(format!("hash-pool-{}", index))
+                .spawn(move || work(receiver, counters))
+                .expect("Could not start password hashing thread");
+        }
+
+        HashPool {
+            sender: Mutex::new(sender),
+            counters,
+            threads: threads.max(1),
+            queue_size
+        }
+    }
+
+    pub fn from_config() -> HashPool {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        HashPool::new(config.hash_pool_threads, config.hash_pool_queue_size)
+    }
+
+    // Queues the job straight away rather than when the future is first polled, so
+    // saturation is known up front
+    pub fn run<F, T>(&self, job: F) -> impl Future<Output = Result<T, HashPoolError>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static
+    {
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        let job: Job = Box::new(move || {
+            let _ = result_sender.send(job());
+        });
+
+        self.counters.queued.fetch_add(1, Ordering::SeqCst);
+
+        let sent = self.sender
+            .lock()
+            .expect("Hash pool sender lock poisoned")
+            .try_send(job);
+
+        let sent = match sent {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+                self.counters.queued.fetch_sub(1, Ordering::SeqCst);
+                self.counters.rejected.fetch_add(1, Ordering::SeqCst);
+                Err(HashPoolError::Saturated)
+            }
+        };
+
+        async move {
+            sent?;
+            result_receiver.await.map_err(|_| HashPoolError::Canceled)
+        }
+    }
+
+    pub fn stats(&self) -> HashPoolStats {
+        HashPoolStats {
+            threads: self.threads,
+            queue_size: self.queue_size,
+            queue_depth: self.counters.queued.load(Ordering::SeqCst),
+            active: self.counters.active.load(Ordering::SeqCst),
+            rejected: self.counters.rejected.load(Ordering::SeqCst)
+        }
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Job>>>, counters: Arc<Counters>) {
+    loop {
+        let job = match receiver.lock().expect("Hash pool receiver lock poisoned").recv() {
+            Ok(job) => job,
+            Err(_) => return
+        };
+
+        counters.queued.fetch_sub(1, Ordering::SeqCst);
+        counters.active.fetch_add(1, Ordering::SeqCst);
+
+        // A panicking job drops its result sender, which the caller sees as Canceled,
+        // and the thread carries on with the next job
+        let _ = catch_unwind(AssertUnwindSafe(job));
+
+        counters.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+lazy_static! {
+    pub static ref HASH_POOL: HashPool = HashPool::from_config();
+}
+
+// Runs a job that hashes or verifies passwords. Where the hashing splits cleanly from
+// the queries around it, as for logins, sign ups and login codes, the queries and any
+// email go through `web::block` instead so they don't hold a hashing thread
+pub async fn run<F, T>(job: F) -> Result<T, HashPoolError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static
+{
+    HASH_POOL.run(job).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use futures::executor::block_on;
+
+    #[test]
+    fn runs_jobs_and_returns_their_result() {
+        let pool = HashPool::new(2, 4);
+
+        assert_eq!(block_on(pool.run(|| 40 + 2)), Ok(42));
+    }
+
+    #[test]
+    fn rejects_jobs_once_the_queue_is_full() {
+        let pool = HashPool::new(1, 1);
+        let (release, blocked) = channel::<()>();
+
+        // Occupies the only thread until released
+        let running = pool.run(move || blocked.recv().unwrap());
+
+        while pool.stats().active == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let queued = pool.run(|| ());
+
+        assert_eq!(pool.stats().queue_depth, 1);
+        assert_eq!(block_on(pool.run(|| ())), Err(HashPoolError::Saturated));
+        assert_eq!(pool.stats().rejected, 1);
+
+        release.send(()).unwrap();
+        assert_eq!(block_on(running), Ok(()));
+        assert_eq!(block_on(queued), Ok(()));
+    }
+
+    #[test]
+    fn survives_a_panicking_job() {
+        let pool = HashPool::new(1, 1);
+
+        assert_eq!(block_on(pool.run(|| panic!("boom"))), Err::<(), _>(HashPoolError::Canceled));
+        assert_eq!(block_on(pool.run(|| 1)), Ok(1));
+    }
+}
@@ -1,13 +1,51 @@
 use jsonwebtoken::{encode, Header, EncodingKey};
 use crate::config::Config;
-use jsonwebtoken::{decode, Validation, DecodingKey};
+use jsonwebtoken::{decode, decode_header, Validation, DecodingKey};
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde::{Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::RwLock;
 
-#[derive(Debug, Serialize, Deserialize)]
+// Tokens are signed with the same key, so the kind stops e.g. an MFA challenge
+// token from being accepted as an access token
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenKind {
+    Access,
+    Refresh,
+    MfaChallenge,
+    WebauthnRegistration,
+    WebauthnAuthentication,
+    PasswordChange,
+}
+
+// Authentication methods references (RFC 8176) for how the user proved who they are
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Amr {
+    Pwd,
+    Otp,
+    Webauthn,
+    // Proved control of the email address, e.g. with a magic link or emailed code
+    Email,
+    Sms,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Claims {
+    pub sub: i32,
     pub exp: usize,
+    pub kind: TokenKind,
+    // Carried over unchanged when tokens are refreshed, so they always describe the
+    // last time the user actually authenticated
+    #[serde(default)]
+    pub amr: Vec<Amr>,
+    #[serde(default)]
+    pub auth_time: usize,
 }
 
-/**
+/*
     #[derive(Debug, Serialize, Deserialize)]
     struct Claims {
         aud: String,         // Optional. Audience
@@ -19,9 +57,89 @@ pub struct Claims {
     }
  */
 
-pub fn jwt_factory(claims: Claims) -> String {
-    let config = Config::from_env().expect("please set some env vars");
-    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(config.jwt_secret_key.as_bytes()));
+pub fn now() -> usize {
+    usize::try_from(Utc::now().timestamp())
+        .unwrap()
+}
+
+pub fn expires_in(duration: Duration) -> usize {
+    usize::try_from((Utc::now() + duration).timestamp())
+        .unwrap()
+}
+
+pub struct RingKey {
+    pub kid: String,
+    pub secret: String,
+    pub created_at: NaiveDateTime
+}
+
+// Signing keys from the database, refreshed by each server in the background. Tokens
+// without a `kid` are signed and verified with `jwt_secret_key`, unless
+// `accept_unkeyed_tokens` is off
+#[derive(Default)]
+struct KeyRing {
+    signing: Option<(String, String)>,
+    verifying: HashMap<String, String>
+}
+
+lazy_static! {
+    static ref KEY_RING: RwLock<KeyRing> = RwLock::new(KeyRing::default());
+}
+
+// A new key only starts signing once it's older than `publish_delay`, by when every
+// server has loaded it and can verify what it signs. Until one is, tokens are signed
+// with `jwt_secret_key` as before the first rotation. Keys are newest first
+fn signing_key(keys: &[RingKey], published_before: NaiveDateTime) -> Option<&RingKey> {
+    keys.iter().find(|key| key.created_at <= published_before)
+}
+
+pub fn set_signing_keys(keys: Vec<RingKey>, publish_delay: Duration) {
+    let signing = signing_key(&keys, Utc::now().naive_utc() - publish_delay)
+        .map(|key| (key.kid.clone(), key.secret.clone()));
+
+    let verifying = keys
+        .into_iter()
+        .map(|key| (key.kid, key.secret))
+        .collect();
+
+    *KEY_RING.write().expect("Key ring lock poisoned") = KeyRing { signing, verifying };
+}
+
+fn verifying_secret(token: &str) -> Result<String, String> {
+    let header = decode_header(token).map_err(|_| String::from("Token is invalid or expired"))?;
+
+    match header.kid {
+        Some(kid) => KEY_RING
+            .read()
+            .expect("Key ring lock poisoned")
+            .verifying
+            .get(&kid)
+            .cloned()
+            .ok_or_else(|| String::from("Token was signed with an unknown or retired key")),
+        None => {
+            let config = Config::from_env()
+                .expect("Must set env vars in config file");
+
+            match config.accept_unkeyed_tokens {
+                true => Ok(config.jwt_secret_key),
+                false => Err(String::from("Token was signed with an unknown or retired key"))
+            }
+        }
+    }
+}
+
+pub fn jwt_factory<T: Serialize>(claims: T) -> String {
+    let signing = KEY_RING.read().expect("Key ring lock poisoned").signing.clone();
+
+    let (header, secret) = match signing {
+        Some((kid, secret)) => (Header { kid: Some(kid), ..Header::default() }, secret),
+        None => {
+            let config = Config::from_env().expect("please set some env vars");
+            (Header::default(), config.jwt_secret_key)
+        }
+    };
+
+    let token = encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes()));
     match token {
         Ok(jwt) => jwt,
         _ => String::from("Could not create token")
@@ -29,15 +147,51 @@ pub fn jwt_factory(claims: Claims) -> String {
 }
 
 pub fn validate_token(token: &str) -> bool {
-    let config = Config::from_env()
-        .expect("Must set env vars in config file");
-    
+    let secret = match verifying_secret(token) {
+        Ok(secret) => secret,
+        Err(_) => return false
+    };
+
+    let validation = Validation { ..Validation::default() };
+    let decoding_key = &DecodingKey::from_secret(secret.as_bytes());
+    decode::<Claims>(token, decoding_key, &validation).is_ok()
+}
+
+pub fn decode_claims<T: DeserializeOwned>(token: &str) -> Result<T, String> {
+    let secret = verifying_secret(token)?;
+
     let validation = Validation { ..Validation::default() };
-    let decoding_key = &DecodingKey::from_secret(config.jwt_secret_key.as_bytes());
-    let decoded_access_token = decode::<Claims>(&token, decoding_key, &validation);
+    let decoding_key = &DecodingKey::from_secret(secret.as_bytes());
+
+    decode::<T>(token, decoding_key, &validation)
+        .map(|token_data| token_data.claims)
+        .map_err(|_| String::from("Token is invalid or expired"))
+}
 
-    match decoded_access_token {
-        Ok(_) => true,
-        Err(_) => false
+pub fn decode_token(token: &str, kind: TokenKind) -> Result<Claims, String> {
+    let claims = decode_claims::<Claims>(token)?;
+
+    match claims.kind == kind {
+        true => Ok(claims),
+        false => Err(String::from("Token is not valid for this purpose"))
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn signs_with_the_newest_key_every_server_has_loaded() {
+    let now = Utc::now().naive_utc();
+    let key = |kid: &str, age_seconds: i64| RingKey {
+        kid: kid.to_string(),
+        secret: String::from("secret"),
+        created_at: now - Duration::seconds(age_seconds)
+    };
+
+    let keys = vec![key("new", 10), key("current", 120), key("old", 3600)];
+    assert_eq!(signing_key(&keys, now - Duration::seconds(60)).map(|key| key.kid.as_str()), Some("current"));
+
+    // Right after the first rotation `jwt_secret_key` keeps signing until the key is published
+    let keys = vec![key("first", 10)];
+    assert!(signing_key(&keys, now - Duration::seconds(60)).is_none());
+
+    assert!(signing_key(&[], now).is_none());
+}
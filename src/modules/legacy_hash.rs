@@ -0,0 +1,105 @@
+use std::num::NonZeroU32;
+
+use ring::{constant_time, pbkdf2};
+
+use crate::modules::hash::HashError;
+
+// passlib's "adapted base64": standard alphabet with '.' for '+', and no padding
+fn decode_ab64(value: &str) -> Result<Vec<u8>, HashError> {
+    base64::decode_config(value.replace('.', "+"), base64::STANDARD_NO_PAD)
+        .map_err(|error| HashError::Malformed(error.to_string()))
+}
+
+fn malformed(format: &str) -> HashError {
+    HashError::Malformed(format!("Expected {}", format))
+}
+
+// `$2a$`, `$2b$` and `$2y$` hashes
+pub fn verify_bcrypt(hash: &str, password: &str) -> Result<bool, HashError> {
+    bcrypt::verify(password, hash)
+        .map_err(|error| HashError::Malformed(error.to_string()))
+}
+
+// `$pbkdf2-sha256$<iterations>$<salt>$<hash>` as written by passlib
+pub fn verify_pbkdf2_sha256(hash: &str, password: &str) -> Result<bool, HashError> {
+    let format = "$pbkdf2-sha256$<iterations>$<salt>$<hash>";
+    let sections: Vec<&str> = hash.split('$').collect();
+
+    if sections.len() != 5 {
+        return Err(malformed(format));
+    }
+
+    let iterations = sections[2]
+        .parse::<u32>()
+        .ok()
+        .and_then(NonZeroU32::new)
+        .ok_or_else(|| malformed(format))?;
+
+    let salt = decode_ab64(sections[3])?;
+    let expected = decode_ab64(sections[4])?;
+
+    Ok(pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, iterations, &salt, password.as_bytes(), &expected).is_ok())
+}
+
+// `$scrypt$ln=<log2 N>,r=<r>,p=<p>$<salt>$<hash>` as written by passlib
+pub fn verify_scrypt(hash: &str, password: &str) -> Result<bool, HashError> {
+    let format = "$scrypt$ln=<log2 N>,r=<r>,p=<p>$<salt>$<hash>";
+    let sections: Vec<&str> = hash.split('$').collect();
+
+    if sections.len() != 5 {
+        return Err(malformed(format));
+    }
+
+    let mut log_n = None;
+    let mut r = None;
+    let mut p = None;
+
+    for param in sections[2].split(',') {
+        match param.split_at(param.find('=').ok_or_else(|| malformed(format))?) {
+            ("ln", value) => log_n = value[1..].parse::<u8>().ok(),
+            ("r", value) => r = value[1..].parse::<u32>().ok(),
+            ("p", value) => p = value[1..].parse::<u32>().ok(),
+            _ => return Err(malformed(format))
+        }
+    }
+
+    let params = match (log_n, r, p) {
+        (Some(log_n), Some(r), Some(p)) => scrypt::ScryptParams::new(log_n, r, p)
+            .map_err(|_| malformed(format))?,
+        _ => return Err(malformed(format))
+    };
+
+    let salt = decode_ab64(sections[3])?;
+    let expected = decode_ab64(sections[4])?;
+    let mut derived = vec![0u8; expected.len()];
+
+    scrypt::scrypt(password.as_bytes(), &salt, &params, &mut derived)
+        .map_err(|_| malformed(format))?;
+
+    Ok(constant_time::verify_slices_are_equal(&derived, &expected).is_ok())
+}
+
+#[test]
+fn verifies_bcrypt() {
+    let hash = bcrypt::hash("correct horse", 4).unwrap();
+
+    assert!(verify_bcrypt(&hash, "correct horse").unwrap());
+    assert!(!verify_bcrypt(&hash, "battery staple").unwrap());
+}
+
+#[test]
+fn verifies_passlib_pbkdf2_sha256() {
+    let hash = "$pbkdf2-sha256$1000$c2FsdHlzYWx0eXNhbHQhIQ$i1kMUoceYfLYPD4WFUJ5V4MPqDibUNI4QrMrZV9hcBE";
+
+    assert!(verify_pbkdf2_sha256(hash, "correct horse").unwrap());
+    assert!(!verify_pbkdf2_sha256(hash, "battery staple").unwrap());
+    assert!(verify_pbkdf2_sha256("$pbkdf2-sha256$many$salt$hash", "correct horse").is_err());
+}
+
+#[test]
+fn verifies_passlib_scrypt() {
+    let hash = "$scrypt$ln=4,r=8,p=1$c2FsdHlzYWx0eXNhbHQhIQ$UxN5SLX/31bRX6b/lgJCXObM5DLhNQEXW7Lf7s/UHrg";
+
+    assert!(verify_scrypt(hash, "correct horse").unwrap());
+    assert!(!verify_scrypt(hash, "battery staple").unwrap());
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime, Utc};
+
+use crate::config::Config;
+
+// Past this many tracked names the oldest one is forgotten for every new one
+const MAX_UNKNOWN_NAMES: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub threshold: i32,
+    pub base: Duration,
+    pub max: Duration
+}
+
+impl LockoutPolicy {
+    pub fn from_config() -> LockoutPolicy {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        LockoutPolicy {
+            threshold: config.lockout_threshold,
+            base: Duration::seconds(config.lockout_base_seconds),
+            max: Duration::seconds(config.lockout_max_seconds)
+        }
+    }
+
+    // Nothing until `threshold` failures in a row, then `base`, doubling with every
+    // further failure up to `max`
+    pub fn lockout_for(&self, failed_attempts: i32) -> Option<Duration> {
+        if failed_attempts < self.threshold {
+            return None;
+        }
+
+        let doublings = (failed_attempts - self.threshold).min(30) as u32;
+        let lockout = Duration::seconds(self.base.num_seconds().saturating_mul(1i64 << doublings));
+
+        Some(if lockout > self.max { self.max } else { lockout })
+    }
+}
+
+struct Failures {
+    count: i32,
+    locked_until: Option<NaiveDateTime>
+}
+
+#[derive(Default)]
+struct Tracked {
+    failures: HashMap<String, Failures>,
+    // Names in the order they were first seen, so the oldest is found without a scan
+    order: VecDeque<String>
+}
+
+// Names without an account are locked out exactly like real accounts, otherwise
+// a lockout response would confirm the account exists
+pub struct UnknownAccounts {
+    tracked: Mutex<Tracked>,
+    capacity: usize
+}
+
+impl Default for UnknownAccounts {
+    fn default() -> UnknownAccounts {
+        UnknownAccounts::new()
+    }
+}
+
+impl UnknownAccounts {
+    pub fn new() -> UnknownAccounts {
+        UnknownAccounts::with_capacity(MAX_UNKNOWN_NAMES)
+    }
+
+    pub fn with_capacity(capacity: usize) -> UnknownAccounts {
+        UnknownAccounts {
+            tracked: Mutex::new(Tracked::default()),
+            capacity
+        }
+    }
+
+    pub fn locked_until(&self, name: &str) -> Option<NaiveDateTime> {
+        let now = Utc::now().naive_utc();
+
+        self.tracked
+            .lock()
+            .ok()?
+            .failures
+            .get(name)
+            .and_then(|failures| failures.locked_until)
+            .filter(|locked_until| *locked_until > now)
+    }
+
+    // Returns when the name is locked until, if this failure locked it
+    pub fn record_failure(&self, name: &str, policy: &LockoutPolicy) -> Option<NaiveDateTime> {
+        let now = Utc::now().naive_utc();
+        let mut tracked = self.tracked.lock().ok()?;
+        let tracked = &mut *tracked;
+
+        if !tracked.failures.contains_key(name) {
+            while tracked.failures.len() >= self.capacity {
+                match tracked.order.pop_front() {
+                    Some(oldest) => { tracked.failures.remove(&oldest); },
+                    None => break
+                }
+            }
+
+            tracked.order.push_back(name.to_string());
+        }
+
+        let entry = tracked.failures.entry(name.to_string()).or_insert(Failures {
+            count: 0,
+            locked_until: None
+        });
+
+        entry.count += 1;
+        entry.locked_until = policy.lockout_for(entry.count).map(|lockout| now + lockout);
+        entry.locked_until
+    }
+}
+
+lazy_static! {
+    pub static ref UNKNOWN_ACCOUNTS: UnknownAccounts = UnknownAccounts::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LockoutPolicy {
+        LockoutPolicy {
+            threshold: 3,
+            base: Duration::seconds(30),
+            max: Duration::seconds(300)
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially_up_to_the_max() {
+        let policy = policy();
+
+        assert_eq!(policy.lockout_for(2), None);
+        assert_eq!(policy.lockout_for(3), Some(Duration::seconds(30)));
+        assert_eq!(policy.lockout_for(4), Some(Duration::seconds(60)));
+        assert_eq!(policy.lockout_for(6), Some(Duration::seconds(240)));
+        assert_eq!(policy.lockout_for(7), Some(Duration::seconds(300)));
+        assert_eq!(policy.lockout_for(1000), Some(Duration::seconds(300)));
+    }
+
+    #[test]
+    fn locks_unknown_names_like_accounts() {
+        let unknown_accounts = UnknownAccounts::new();
+        let policy = policy();
+
+        assert_eq!(unknown_accounts.record_failure("nobody", &policy), None);
+        assert_eq!(unknown_accounts.record_failure("nobody", &policy), None);
+        assert!(unknown_accounts.locked_until("nobody").is_none());
+
+        assert!(unknown_accounts.record_failure("nobody", &policy).is_some());
+        assert!(unknown_accounts.locked_until("nobody").is_some());
+        assert!(unknown_accounts.locked_until("somebody").is_none());
+    }
+
+    #[test]
+    fn forgets_the_oldest_names_past_the_capacity() {
+        let unknown_accounts = UnknownAccounts::with_capacity(2);
+        let policy = LockoutPolicy { threshold: 1, ..policy() };
+
+        unknown_accounts.record_failure("first", &policy);
+        unknown_accounts.record_failure("second", &policy);
+        unknown_accounts.record_failure("third", &policy);
+
+        assert!(unknown_accounts.locked_until("first").is_none());
+        assert!(unknown_accounts.locked_until("second").is_some());
+        assert!(unknown_accounts.locked_until("third").is_some());
+        assert_eq!(unknown_accounts.tracked.lock().unwrap().failures.len(), 2);
+    }
+}
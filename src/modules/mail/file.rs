@@ -0,0 +1,74 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Utc;
+
+use super::{Email, Mailer};
+
+// Development and test stand-in for SMTP, writes each email to its own file in
+// `directory`, or to stdout when there is no directory
+pub struct FileMailer {
+    directory: Option<PathBuf>
+}
+
+impl FileMailer {
+    pub fn new(directory: Option<PathBuf>) -> FileMailer {
+        FileMailer { directory }
+    }
+}
+
+fn format_email(email: &Email) -> String {
+    format!(
+        "To: {}\nSubject: {}\n\n{}\n\n--- HTML ---\n\n{}",
+        email.to, email.subject, email.text, email.html
+    )
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        match &self.directory {
+            Some(directory) => {
+                fs::create_dir_all(directory)
+                    .map_err(|error| format!("Could not create mail directory: {}", error))?;
+
+                let recipient: String = email.to
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '@' { c } else { '_' })
+                    .collect();
+
+                let file_name = format!("{}-{}.eml", Utc::now().timestamp_nanos(), recipient);
+
+                fs::write(directory.join(file_name), format_email(email))
+                    .map_err(|error| format!("Could not write email: {}", error))
+            },
+            None => {
+                println!("{}", format_email(email));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[test]
+fn writes_one_file_per_email() {
+    let directory = std::env::temp_dir().join(format!("mail-test-{}", Utc::now().timestamp_nanos()));
+    let mailer = FileMailer::new(Some(directory.clone()));
+
+    let email = Email {
+        to: String::from("clara@email.com"),
+        subject: String::from("Hello"),
+        text: String::from("Hi Clara"),
+        html: String::from("<p>Hi Clara</p>")
+    };
+
+    mailer.send(&email).unwrap();
+
+    let files: Vec<_> = fs::read_dir(&directory).unwrap().collect();
+    assert_eq!(files.len(), 1);
+
+    let contents = fs::read_to_string(files[0].as_ref().unwrap().path()).unwrap();
+    assert!(contents.contains("To: clara@email.com"));
+    assert!(contents.contains("<p>Hi Clara</p>"));
+
+    fs::remove_dir_all(directory).unwrap();
+}
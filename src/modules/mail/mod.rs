@@ -0,0 +1,48 @@
+pub mod file;
+pub mod queue;
+pub mod smtp;
+pub mod templates;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::config::Config;
+use self::file::FileMailer;
+use self::queue::MailQueue;
+use self::smtp::SmtpMailer;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String
+}
+
+// Implementations only need to be Send, the queue owns the mailer on its own thread
+pub trait Mailer: Send {
+    fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+fn mailer_from_config(config: &Config) -> Box<dyn Mailer> {
+    match config.mail_transport.as_ref() {
+        "smtp" => Box::new(SmtpMailer::from_config(config)),
+        "file" => Box::new(FileMailer::new(Some(PathBuf::from(&config.mail_file_directory)))),
+        _ => Box::new(FileMailer::new(None))
+    }
+}
+
+lazy_static! {
+    static ref MAIL_QUEUE: MailQueue = {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        MailQueue::start(mailer_from_config(&config), config.mail_max_attempts, Duration::from_secs(1))
+    };
+}
+
+// Only renders and queues the email so request handlers never wait on delivery
+pub fn send_template(to: &str, locale: &str, template: &str, variables: &[(&str, &str)]) -> Result<(), String> {
+    let email = templates::render(template, locale, to, variables)?;
+    MAIL_QUEUE.enqueue(email)
+}
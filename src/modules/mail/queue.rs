@@ -0,0 +1,180 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::{Email, Mailer};
+
+// How long the worker waits for new mail when it has no retries scheduled
+const IDLE_WAIT: Duration = Duration::from_secs(60);
+// The longest wait between retries, however large `max_attempts` is
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+struct Delivery {
+    email: Email,
+    attempt: u32
+}
+
+pub struct MailQueue {
+    // std's Sender isn't Sync, the lock is only held long enough to hand the email over
+    sender: Mutex<Sender<Email>>
+}
+
+impl MailQueue {
+    // Failed deliveries are retried with exponential backoff starting at
+    // `base_backoff`, and dropped after `max_attempts`
+    pub fn start(mailer: Box<dyn Mailer>, max_attempts: u32, base_backoff: Duration) -> MailQueue {
+        let (sender, receiver) = channel();
+
+        thread::spawn(move || deliver_forever(mailer, receiver, max_attempts, base_backoff));
+
+        MailQueue { sender: Mutex::new(sender) }
+    }
+
+    pub fn enqueue(&self, email: Email) -> Result<(), String> {
+        self.sender
+            .lock()
+            .map_err(|_| String::from("Mail queue is unavailable"))?
+            .send(email)
+            .map_err(|_| String::from("Mail queue has stopped"))
+    }
+}
+
+// Doubles from `base_backoff` with each attempt, without overflowing past `MAX_BACKOFF`
+fn backoff(base_backoff: Duration, attempt: u32) -> Duration {
+    2u32.checked_pow(attempt.saturating_sub(1))
+        .and_then(|factor| base_backoff.checked_mul(factor))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+}
+
+fn deliver(mailer: &dyn Mailer, delivery: Delivery, max_attempts: u32, base_backoff: Duration, retries: &mut Vec<(Instant, Delivery)>) {
+    match mailer.send(&delivery.email) {
+        Ok(_) => {},
+        Err(error) if delivery.attempt >= max_attempts => {
+            log::error!("Giving up on email to {} after {} attempts: {}", delivery.email.to, delivery.attempt, error);
+        },
+        Err(error) => {
+            let backoff = backoff(base_backoff, delivery.attempt);
+            log::warn!("Could not send email to {}, retrying in {:?}: {}", delivery.email.to, backoff, error);
+
+            retries.push((Instant::now() + backoff, Delivery {
+                email: delivery.email,
+                attempt: delivery.attempt + 1
+            }));
+        }
+    }
+}
+
+fn deliver_forever(mailer: Box<dyn Mailer>, receiver: Receiver<Email>, max_attempts: u32, base_backoff: Duration) {
+    let mut retries: Vec<(Instant, Delivery)> = vec![];
+
+    loop {
+        let wait = retries
+            .iter()
+            .map(|(due, _)| due.saturating_duration_since(Instant::now()))
+            .min()
+            .unwrap_or(IDLE_WAIT);
+
+        match receiver.recv_timeout(wait) {
+            Ok(email) => deliver(&*mailer, Delivery { email, attempt: 1 }, max_attempts, base_backoff, &mut retries),
+            Err(RecvTimeoutError::Timeout) => {},
+            // Nothing new can arrive, finish off any retries before stopping
+            Err(RecvTimeoutError::Disconnected) if retries.is_empty() => return,
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(wait)
+        }
+
+        let now = Instant::now();
+        let (due, waiting): (Vec<_>, Vec<_>) = retries
+            .drain(..)
+            .partition(|(due, _)| *due <= now);
+
+        retries = waiting;
+
+        for (_, delivery) in due {
+            deliver(&*mailer, delivery, max_attempts, base_backoff, &mut retries);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Fails the first `failures` sends, then records what it delivers
+    struct FlakyMailer {
+        failures: Mutex<u32>,
+        delivered: Arc<Mutex<Vec<Email>>>
+    }
+
+    impl Mailer for FlakyMailer {
+        fn send(&self, email: &Email) -> Result<(), String> {
+            let mut failures = self.failures.lock().unwrap();
+
+            if *failures > 0 {
+                *failures -= 1;
+                return Err(String::from("SMTP server unavailable"));
+            }
+
+            self.delivered.lock().unwrap().push(email.clone());
+            Ok(())
+        }
+    }
+
+    fn email() -> Email {
+        Email {
+            to: String::from("clara@email.com"),
+            subject: String::from("Hello"),
+            text: String::from("Hi Clara"),
+            html: String::from("<p>Hi Clara</p>")
+        }
+    }
+
+    fn wait_for_deliveries(delivered: &Arc<Mutex<Vec<Email>>>, count: usize) -> usize {
+        let deadline = Instant::now() + Duration::from_secs(2);
+
+        while delivered.lock().unwrap().len() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        delivered.lock().unwrap().len()
+    }
+
+    #[test]
+    fn caps_the_backoff() {
+        assert_eq!(backoff(Duration::from_secs(1), 1), Duration::from_secs(1));
+        assert_eq!(backoff(Duration::from_secs(1), 4), Duration::from_secs(8));
+        assert_eq!(backoff(Duration::from_secs(1), 20), MAX_BACKOFF);
+        assert_eq!(backoff(Duration::from_secs(1), 40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn retries_until_delivered() {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let mailer = FlakyMailer { failures: Mutex::new(2), delivered: delivered.clone() };
+        let queue = MailQueue::start(Box::new(mailer), 5, Duration::from_millis(1));
+
+        queue.enqueue(email()).unwrap();
+
+        assert_eq!(wait_for_deliveries(&delivered, 1), 1);
+        assert_eq!(delivered.lock().unwrap()[0], email());
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let delivered = Arc::new(Mutex::new(vec![]));
+        let mailer = FlakyMailer { failures: Mutex::new(3), delivered: delivered.clone() };
+        let queue = MailQueue::start(Box::new(mailer), 3, Duration::from_millis(1));
+
+        queue.enqueue(email()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The next email goes through, so only the abandoned one is missing
+        let mut second = email();
+        second.subject = String::from("Second");
+        queue.enqueue(second.clone()).unwrap();
+
+        assert_eq!(wait_for_deliveries(&delivered, 1), 1);
+        assert_eq!(delivered.lock().unwrap()[0], second);
+    }
+}
@@ -0,0 +1,110 @@
+use lettre::{ClientSecurity, ClientTlsParameters, SmtpClient, Transport};
+use lettre::smtp::authentication::Credentials;
+use lettre_email::EmailBuilder;
+use native_tls::{Protocol, TlsConnector};
+
+use crate::config::Config;
+use super::{Email, Mailer};
+
+// The submissions port, where the connection starts with TLS rather than upgrading to it
+const SUBMISSIONS_PORT: u16 = 465;
+const SMTP_PORT: u16 = 25;
+
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    // Implicit TLS on the submissions port, STARTTLS on any other. Without it, plain SMTP
+    // for local relays
+    tls: bool,
+    credentials: Option<(String, String)>,
+    from: String
+}
+
+impl SmtpMailer {
+    pub fn from_config(config: &Config) -> SmtpMailer {
+        let credentials = match (&config.smtp_username, &config.smtp_password) {
+            (Some(username), Some(password)) => Some((username.to_string(), password.to_string())),
+            _ => None
+        };
+
+        SmtpMailer {
+            host: config.smtp_host.to_string(),
+            port: config.smtp_port.unwrap_or(if config.smtp_tls { SUBMISSIONS_PORT } else { SMTP_PORT }),
+            tls: config.smtp_tls,
+            credentials,
+            from: config.mail_from.to_string()
+        }
+    }
+}
+
+impl SmtpMailer {
+    fn security(&self) -> Result<ClientSecurity, String> {
+        if !self.tls {
+            return Ok(ClientSecurity::None);
+        }
+
+        let connector = TlsConnector::builder()
+            .min_protocol_version(Some(Protocol::Tlsv12))
+            .build()
+            .map_err(|error| format!("Could not set up TLS: {}", error))?;
+
+        // The certificate is checked against the host we connect to
+        let parameters = ClientTlsParameters::new(self.host.to_string(), connector);
+
+        Ok(match self.port {
+            SUBMISSIONS_PORT => ClientSecurity::Wrapper(parameters),
+            _ => ClientSecurity::Required(parameters)
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, email: &Email) -> Result<(), String> {
+        let message = EmailBuilder::new()
+            .to(email.to.to_string())
+            .from(self.from.to_string())
+            .subject(email.subject.to_string())
+            .alternative(email.html.to_string(), email.text.to_string())
+            .build()
+            .map_err(|error| format!("Could not build email: {}", error))?;
+
+        // They would cross the network in the clear
+        if self.credentials.is_some() && !self.tls {
+            return Err(String::from("Refusing to send SMTP credentials without TLS, set smtp_tls or drop smtp_username"));
+        }
+
+        let client = SmtpClient::new((self.host.as_str(), self.port), self.security()?)
+            .map_err(|error| format!("Could not connect to SMTP server: {}", error))?;
+
+        let client = match &self.credentials {
+            Some((username, password)) => client.credentials(Credentials::new(username.to_string(), password.to_string())),
+            None => client
+        };
+
+        client
+            .transport()
+            .send(message.into())
+            .map(|_| ())
+            .map_err(|error| format!("Could not send email: {}", error))
+    }
+}
+
+#[test]
+fn refuses_credentials_without_tls() {
+    let mailer = SmtpMailer {
+        host: String::from("localhost"),
+        port: SMTP_PORT,
+        tls: false,
+        credentials: Some((String::from("mailer"), String::from("secret"))),
+        from: String::from("no-reply@email.com")
+    };
+
+    let email = Email {
+        to: String::from("clara@email.com"),
+        subject: String::from("Hello"),
+        text: String::from("Hi Clara"),
+        html: String::from("<p>Hi Clara</p>")
+    };
+
+    assert!(mailer.send(&email).unwrap_err().contains("without TLS"));
+}
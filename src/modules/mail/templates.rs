@@ -0,0 +1,142 @@
+use super::Email;
+
+const DEFAULT_LOCALE: &str = "en";
+
+struct Template {
+    name: &'static str,
+    locale: &'static str,
+    // The first line of the text variant is "Subject: ..."
+    text: &'static str,
+    html: &'static str
+}
+
+static TEMPLATES: &[Template] = &[
+    Template {
+        name: "verify_email",
+        locale: "en",
+        text: include_str!("../../../templates/email/verify_email.en.txt"),
+        html: include_str!("../../../templates/email/verify_email.en.html")
+    },
+    Template {
+        name: "verify_email",
+        locale: "es",
+        text: include_str!("../../../templates/email/verify_email.es.txt"),
+        html: include_str!("../../../templates/email/verify_email.es.html")
+    },
+    Template {
+        name: "password_reset",
+        locale: "en",
+        text: include_str!("../../../templates/email/password_reset.en.txt"),
+        html: include_str!("../../../templates/email/password_reset.en.html")
+    },
+    Template {
+        name: "password_reset",
+        locale: "es",
+        text: include_str!("../../../templates/email/password_reset.es.txt"),
+        html: include_str!("../../../templates/email/password_reset.es.html")
+    },
+    Template {
+        name: "magic_link",
+        locale: "en",
+        text: include_str!("../../../templates/email/magic_link.en.txt"),
+        html: include_str!("../../../templates/email/magic_link.en.html")
+    },
+    Template {
+        name: "magic_link",
+        locale: "es",
+        text: include_str!("../../../templates/email/magic_link.es.txt"),
+        html: include_str!("../../../templates/email/magic_link.es.html")
+    },
+    Template {
+        name: "login_code",
+        locale: "en",
+        text: include_str!("../../../templates/email/login_code.en.txt"),
+        html: include_str!("../../../templates/email/login_code.en.html")
+    },
+    Template {
+        name: "login_code",
+        locale: "es",
+        text: include_str!("../../../templates/email/login_code.es.txt"),
+        html: include_str!("../../../templates/email/login_code.es.html")
+    },
+];
+
+// Tries "pt-BR", then "pt", then the default locale
+fn find(name: &str, locale: &str) -> Option<&'static Template> {
+    let language = locale.split('-').next().unwrap_or(DEFAULT_LOCALE);
+
+    [locale, language, DEFAULT_LOCALE]
+        .iter()
+        .filter_map(|candidate| {
+            TEMPLATES
+                .iter()
+                .find(|template| template.name == name && template.locale.eq_ignore_ascii_case(candidate))
+        })
+        .next()
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn fill(template: &str, variables: &[(&str, String)]) -> String {
+    variables
+        .iter()
+        .fold(template.to_string(), |filled, (key, value)| {
+            filled.replace(&format!("{{{{{}}}}}", key), value)
+        })
+}
+
+pub fn render(name: &str, locale: &str, to: &str, variables: &[(&str, &str)]) -> Result<Email, String> {
+    let template = find(name, locale)
+        .ok_or_else(|| format!("No email template named {}", name))?;
+
+    let mut text_lines = template.text.splitn(2, '\n');
+    let subject = text_lines
+        .next()
+        .and_then(|line| line.strip_prefix("Subject:"))
+        .ok_or_else(|| format!("Email template {} has no subject line", name))?;
+    let text = text_lines.next().unwrap_or_default();
+
+    let plain: Vec<(&str, String)> = variables.iter().map(|(key, value)| (*key, value.to_string())).collect();
+    let escaped: Vec<(&str, String)> = variables.iter().map(|(key, value)| (*key, escape_html(value))).collect();
+
+    Ok(Email {
+        to: to.to_string(),
+        subject: fill(subject.trim(), &plain),
+        text: fill(text.trim_start(), &plain),
+        html: fill(template.html, &escaped)
+    })
+}
+
+#[test]
+fn renders_text_and_html_variants() {
+    let email = render("verify_email", "en", "clara@email.com", &[
+        ("name", "<clara>"),
+        ("link", "http://localhost:3000/verify-email?token=abc"),
+        ("expires_in_hours", "48")
+    ]).unwrap();
+
+    assert_eq!(email.to, "clara@email.com");
+    assert_eq!(email.subject, "Verify your email address");
+    assert!(email.text.starts_with("Hi <clara>,"));
+    assert!(email.text.contains("http://localhost:3000/verify-email?token=abc"));
+    assert!(email.html.contains("Hi &lt;clara&gt;,"));
+    assert!(!email.html.contains("{{"));
+}
+
+#[test]
+fn falls_back_to_language_then_default_locale() {
+    let spanish = render("password_reset", "es-MX", "clara@email.com", &[]).unwrap();
+    assert_eq!(spanish.subject, "Restablece tu contraseña");
+
+    let english = render("password_reset", "fr", "clara@email.com", &[]).unwrap();
+    assert_eq!(english.subject, "Reset your password");
+
+    assert!(render("unknown", "en", "clara@email.com", &[]).is_err());
+}
@@ -1,2 +1,11 @@
 pub mod jwt;
-pub mod hash;
\ No newline at end of file
+pub mod hash;
+pub mod hash_pool;
+pub mod legacy_hash;
+pub mod lockout;
+pub mod mail;
+pub mod password_policy;
+pub mod rate_limit;
+pub mod sms;
+pub mod totp;
+pub mod webauthn;
\ No newline at end of file
@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use ring::digest;
+
+use crate::config::Config;
+
+// One reason a submitted value was rejected, `code` is stable for clients to match on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub code: String,
+    pub message: String
+}
+
+impl FieldError {
+    pub fn password(code: &str, message: String) -> FieldError {
+        FieldError {
+            field: String::from("password"),
+            code: code.to_string(),
+            message
+        }
+    }
+}
+
+// Anything the sorted hash list can be read from, a file outside of tests
+trait SortedHashes: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> SortedHashes for T {}
+
+// SHA-1 hashes of known breached passwords, one uppercase hex hash per line sorted by
+// hash, as in the Pwned Passwords "ordered by hash" download (anything after a ':' on
+// the line is ignored). The list is binary searched where it is rather than loaded, as
+// the full download has hundreds of millions of lines
+pub struct BreachedPasswords {
+    // None without a `breached_passwords_file`, which skips the check
+    sorted_hashes: Option<Mutex<Box<dyn SortedHashes>>>
+}
+
+impl BreachedPasswords {
+    pub fn none() -> BreachedPasswords {
+        BreachedPasswords { sorted_hashes: None }
+    }
+
+    fn from_sorted<T: Read + Seek + Send + 'static>(sorted_hashes: T) -> BreachedPasswords {
+        BreachedPasswords { sorted_hashes: Some(Mutex::new(Box::new(sorted_hashes))) }
+    }
+
+    fn load(path: &Option<String>) -> BreachedPasswords {
+        match path {
+            Some(path) => match File::open(path) {
+                Ok(file) => BreachedPasswords::from_sorted(file),
+                Err(error) => {
+                    log::error!("Could not open breached passwords file {}: {}", path, error);
+                    BreachedPasswords::none()
+                }
+            },
+            None => BreachedPasswords::none()
+        }
+    }
+
+    pub fn contains(&self, password: &str) -> bool {
+        let sorted_hashes = match &self.sorted_hashes {
+            Some(sorted_hashes) => sorted_hashes,
+            None => return false
+        };
+
+        let hash = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        let hex: String = hash.as_ref().iter().map(|byte| format!("{:02X}", byte)).collect();
+
+        let mut sorted_hashes = match sorted_hashes.lock() {
+            Ok(sorted_hashes) => sorted_hashes,
+            Err(_) => return false
+        };
+
+        // Like a missing file, an unreadable one doesn't stop passwords being set
+        search_sorted(&mut **sorted_hashes, &hex).unwrap_or_else(|error| {
+            log::error!("Could not search breached passwords: {}", error);
+            false
+        })
+    }
+}
+
+// The first line starting at or after `position` and where the one after it starts,
+// None past the last line
+fn line_from(sorted_hashes: &mut dyn SortedHashes, position: u64) -> io::Result<Option<(String, u64)>> {
+    let start = position.saturating_sub(1);
+    sorted_hashes.seek(SeekFrom::Start(start))?;
+
+    let mut reader = BufReader::with_capacity(256, sorted_hashes);
+    let mut skipped = 0;
+
+    // Unless `position` starts the file, the rest of the line `position - 1` is on is skipped,
+    // which is nothing when `position` starts a line
+    if position > 0 {
+        skipped = reader.read_until(b'\n', &mut Vec::new())? as u64;
+    }
+
+    let mut line = String::new();
+    let read = reader.read_line(&mut line)? as u64;
+
+    match read {
+        0 => Ok(None),
+        _ => Ok(Some((line, start + skipped + read)))
+    }
+}
+
+fn search_sorted(sorted_hashes: &mut dyn SortedHashes, hex: &str) -> io::Result<bool> {
+    // Byte offsets the matching line could start in
+    let mut low = 0;
+    let mut high = sorted_hashes.seek(SeekFrom::End(0))?;
+
+    while low < high {
+        let middle = low + (high - low) / 2;
+
+        let (line, next_line) = match line_from(sorted_hashes, middle)? {
+            Some(found) => found,
+            None => {
+                high = middle;
+                continue;
+            }
+        };
+
+        let line_hash = line.split(':').next().unwrap_or("").trim().to_uppercase();
+
+        match line_hash.as_str().cmp(hex) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => low = next_line,
+            Ordering::Greater => high = middle
+        }
+    }
+
+    Ok(false)
+}
+
+lazy_static! {
+    static ref BREACHED_PASSWORDS: BreachedPasswords = {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        BreachedPasswords::load(&config.breached_passwords_file)
+    };
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    // zxcvbn score from 0 (guessable in a few tries) to 4 (very unguessable)
+    pub min_strength: u8
+}
+
+impl PasswordPolicy {
+    pub fn from_config() -> PasswordPolicy {
+        let config = Config::from_env()
+            .expect("Must set env vars in config file");
+
+        PasswordPolicy {
+            min_length: config.password_min_length,
+            min_strength: config.password_min_strength
+        }
+    }
+
+    // `user_inputs` are the user's own details, e.g. name and email, which must not
+    // appear in the password and make it weaker when guessed from
+    pub fn check(&self, password: &str, user_inputs: &[&str], breached: &BreachedPasswords) -> Vec<FieldError> {
+        let mut errors = vec![];
+
+        if password.chars().count() < self.min_length {
+            errors.push(FieldError::password("too_short", format!("Password must be at least {} characters", self.min_length)));
+        }
+
+        let lowercase_password = password.to_lowercase();
+
+        let contains_user_input = user_inputs
+            .iter()
+            .flat_map(|input| vec![input.to_string(), input.split('@').next().unwrap_or("").to_string()])
+            .map(|input| input.trim().to_lowercase())
+            .any(|input| input.chars().count() >= 3 && lowercase_password.contains(&input));
+
+        if contains_user_input {
+            errors.push(FieldError::password("contains_personal_info", String::from("Password must not contain your name or email")));
+        }
+
+        let strength = zxcvbn::zxcvbn(password, user_inputs)
+            .map(|entropy| entropy.score())
+            .unwrap_or(0);
+
+        if strength < self.min_strength {
+            errors.push(FieldError::password("too_weak", String::from("Password is too easy to guess")));
+        }
+
+        if breached.contains(password) {
+            errors.push(FieldError::password("breached", String::from("Password has appeared in a data breach")));
+        }
+
+        errors
+    }
+}
+
+pub fn validate_password(password: &str, user_inputs: &[&str]) -> Result<(), Vec<FieldError>> {
+    let errors = PasswordPolicy::from_config().check(password, user_inputs, &BREACHED_PASSWORDS);
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy { min_length: 8, min_strength: 3 }
+    }
+
+    fn codes(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|error| error.code).collect()
+    }
+
+    #[test]
+    fn accepts_a_strong_password() {
+        let breached = BreachedPasswords::none();
+
+        assert!(policy().check("vX9#qLp2!mZr7wTe", &["clara", "clara@email.com"], &breached).is_empty());
+    }
+
+    #[test]
+    fn rejects_short_and_weak_passwords() {
+        let breached = BreachedPasswords::none();
+        let codes = codes(policy().check("123", &[], &breached));
+
+        assert!(codes.contains(&String::from("too_short")));
+        assert!(codes.contains(&String::from("too_weak")));
+    }
+
+    #[test]
+    fn rejects_passwords_containing_name_or_email() {
+        let breached = BreachedPasswords::none();
+
+        let with_name = codes(policy().check("Clarabelle-9137#x", &["clara", "cb@email.com"], &breached));
+        let with_mailbox = codes(policy().check("x!clara.b-2231", &["cb", "clara.b@email.com"], &breached));
+        let unrelated = codes(policy().check("vX9#qLp2!mZr7wTe", &["clara", "clara.b@email.com"], &breached));
+
+        assert!(with_name.contains(&String::from("contains_personal_info")));
+        assert!(with_mailbox.contains(&String::from("contains_personal_info")));
+        assert!(!unrelated.contains(&String::from("contains_personal_info")));
+    }
+
+    #[test]
+    fn rejects_breached_passwords() {
+        let breached = BreachedPasswords::from_sorted(Cursor::new("874572E7A5AE6A49466A6AC578B98ADBA78C6AA6:3645\n"));
+
+        assert!(codes(policy().check("Tr0ub4dor&3", &[], &breached)).contains(&String::from("breached")));
+        assert!(!codes(policy().check("vX9#qLp2!mZr7wTe", &[], &breached)).contains(&String::from("breached")));
+    }
+
+    #[test]
+    fn searches_the_sorted_hashes() {
+        let hashes: Vec<String> = (0..500u32)
+            .map(|index| format!("{:040X}", u64::from(index) * 7 + 3))
+            .collect();
+        let contents: String = hashes.iter().map(|hash| format!("{}:{}\r\n", hash, hash.len())).collect();
+        let mut sorted_hashes = Cursor::new(contents);
+
+        for hash in &hashes {
+            assert!(search_sorted(&mut sorted_hashes, hash).unwrap(), "{} is in the list", hash);
+        }
+
+        for missing in &[0u64, 4, 1000, 5000] {
+            let hash = format!("{:040X}", missing);
+            assert!(!search_sorted(&mut sorted_hashes, &hash).unwrap(), "{} isn't in the list", hash);
+        }
+
+        assert!(!search_sorted(&mut Cursor::new(""), &hashes[0]).unwrap());
+    }
+}
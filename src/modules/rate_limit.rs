@@ -0,0 +1,276 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::{Connection, RunQueryDsl};
+
+use crate::config::Config;
+use crate::db::db_connection::PgPool;
+
+// Past this many buckets the least recently used one is dropped for every new one.
+// A dropped bucket starts over full, and the least recently used ones mostly are anyway
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+// A token bucket holding up to `burst` requests, refilled at `per_minute`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: NaiveDateTime
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // Seconds until a request would be allowed, 0 when this one was
+    pub retry_after: u64,
+    // Seconds until the bucket is full again
+    pub reset: u64
+}
+
+impl Limit {
+    fn refill_per_second(&self) -> f64 {
+        f64::from(self.per_minute.max(1)) / 60.0
+    }
+
+    // Refills the bucket for the time since it was last used, then spends a token if there is one
+    pub fn take(&self, bucket: Option<Bucket>, now: NaiveDateTime) -> (Bucket, Decision) {
+        let burst = f64::from(self.burst);
+        let rate = self.refill_per_second();
+
+        let available = match bucket {
+            Some(bucket) => {
+                let elapsed = (now - bucket.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+                (bucket.tokens + elapsed * rate).min(burst)
+            },
+            None => burst
+        };
+
+        let allowed = available >= 1.0;
+        let tokens = if allowed { available - 1.0 } else { available };
+
+        let decision = Decision {
+            allowed,
+            limit: self.burst,
+            remaining: tokens.floor() as u32,
+            retry_after: if allowed { 0 } else { ((1.0 - tokens) / rate).ceil() as u64 },
+            reset: ((burst - tokens) / rate).ceil() as u64
+        };
+
+        (Bucket { tokens, updated_at: now }, decision)
+    }
+}
+
+// Implementations are shared by every worker, so they have to be thread safe
+pub trait RateLimitStore: Send + Sync {
+    fn take(&self, key: &str, limit: &Limit) -> Result<Decision, String>;
+}
+
+// What the rate limit middleware looks for in app data
+pub type RateLimits = Arc<dyn RateLimitStore>;
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, (Bucket, u64)>,
+    // Keys by when they were last used, so the least recently used is found without a scan
+    by_use: BTreeMap<u64, String>,
+    next_use: u64
+}
+
+// Per process, so each instance of the service enforces its own limits
+pub struct MemoryStore {
+    buckets: Mutex<Buckets>,
+    capacity: usize
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        MemoryStore::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::with_capacity(MAX_MEMORY_BUCKETS)
+    }
+
+    pub fn with_capacity(capacity: usize) -> MemoryStore {
+        MemoryStore {
+            buckets: Mutex::new(Buckets::default()),
+            capacity
+        }
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take(&self, key: &str, limit: &Limit) -> Result<Decision, String> {
+        let now = Utc::now().naive_utc();
+        let mut buckets = self.buckets
+            .lock()
+            .map_err(|_| String::from("Rate limit store is unavailable"))?;
+        let buckets = &mut *buckets;
+
+        let previous = buckets.by_key.remove(key);
+
+        if let Some((_, last_use)) = previous {
+            buckets.by_use.remove(&last_use);
+        }
+
+        while buckets.by_key.len() >= self.capacity {
+            let least_recent = buckets.by_use.keys().next().copied();
+
+            match least_recent.and_then(|last_use| buckets.by_use.remove(&last_use)) {
+                Some(least_recent_key) => { buckets.by_key.remove(&least_recent_key); },
+                None => break
+            }
+        }
+
+        let (bucket, decision) = limit.take(previous.map(|(bucket, _)| bucket), now);
+
+        let this_use = buckets.next_use;
+        buckets.next_use += 1;
+        buckets.by_use.insert(this_use, key.to_string());
+        buckets.by_key.insert(key.to_string(), (bucket, this_use));
+
+        Ok(decision)
+    }
+}
+
+// Shared by every instance pointed at the same database, each bucket is a row that
+// is locked while it is updated
+pub struct PostgresStore {
+    pool: PgPool
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> PostgresStore {
+        PostgresStore { pool }
+    }
+}
+
+impl RateLimitStore for PostgresStore {
+    fn take(&self, key: &str, limit: &Limit) -> Result<Decision, String> {
+        use crate::schema::rate_limit_buckets::dsl::*;
+        use crate::diesel::QueryDsl;
+        use crate::diesel::ExpressionMethods;
+
+        let connection = self.pool
+            .get()
+            .map_err(|error| format!("Could not connect to rate limit store: {}", error))?;
+
+        connection.transaction::<_, diesel::result::Error, _>(|| {
+            let now = Utc::now().naive_utc();
+
+            diesel::insert_into(rate_limit_buckets)
+                .values((bucket_key.eq(key), tokens.eq(f64::from(limit.burst)), updated_at.eq(now)))
+                .on_conflict_do_nothing()
+                .execute(&connection)?;
+
+            let (current_tokens, last_updated_at) = rate_limit_buckets
+                .filter(bucket_key.eq(key))
+                .select((tokens, updated_at))
+                .for_update()
+                .first::<(f64, NaiveDateTime)>(&connection)?;
+
+            let (bucket, decision) = limit.take(Some(Bucket { tokens: current_tokens, updated_at: last_updated_at }), now);
+
+            diesel::update(rate_limit_buckets.filter(bucket_key.eq(key)))
+                .set((tokens.eq(bucket.tokens), updated_at.eq(bucket.updated_at)))
+                .execute(&connection)?;
+
+            Ok(decision)
+        })
+        .map_err(|error| format!("Could not update rate limit: {}", error))
+    }
+}
+
+// The Postgres store shares the app's pool rather than opening connections of its own
+pub fn store_from_config(config: &Config, pg_pool: Option<&PgPool>) -> Result<RateLimits, String> {
+    match (config.rate_limit_store.as_ref(), pg_pool) {
+        ("postgres", Some(pool)) => Ok(Arc::new(PostgresStore::new(pool.clone()))),
+        ("postgres", None) => Err(String::from("rate_limit_store \"postgres\" needs a Postgres database_url")),
+        _ => Ok(Arc::new(MemoryStore::new()))
+    }
+}
+
+lazy_static! {
+    // For apps that don't register a store, such as the HTTP tests
+    pub static ref DEFAULT_STORE: RateLimits = Arc::new(MemoryStore::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn limit() -> Limit {
+        Limit { burst: 2, per_minute: 6 }
+    }
+
+    #[test]
+    fn spends_the_burst_then_waits_for_refill() {
+        let limit = limit();
+        let start = Utc::now().naive_utc();
+
+        let (bucket, first) = limit.take(None, start);
+        assert!(first.allowed);
+        assert_eq!(first.remaining, 1);
+
+        let (bucket, second) = limit.take(Some(bucket), start);
+        assert!(second.allowed);
+        assert_eq!(second.remaining, 0);
+
+        let (bucket, third) = limit.take(Some(bucket), start);
+        assert!(!third.allowed);
+        assert_eq!(third.retry_after, 10);
+        assert_eq!(third.reset, 20);
+
+        // One token comes back every 10 seconds
+        let (_, fourth) = limit.take(Some(bucket), start + Duration::seconds(10));
+        assert!(fourth.allowed);
+    }
+
+    #[test]
+    fn refill_never_exceeds_the_burst() {
+        let limit = limit();
+        let start = Utc::now().naive_utc();
+
+        let (bucket, _) = limit.take(None, start);
+        let (_, decision) = limit.take(Some(bucket), start + Duration::hours(1));
+
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn memory_store_keeps_buckets_apart() {
+        let store = MemoryStore::new();
+        let limit = Limit { burst: 1, per_minute: 1 };
+
+        assert!(store.take("login:ip:127.0.0.1", &limit).unwrap().allowed);
+        assert!(!store.take("login:ip:127.0.0.1", &limit).unwrap().allowed);
+        assert!(store.take("login:ip:127.0.0.2", &limit).unwrap().allowed);
+    }
+
+    #[test]
+    fn memory_store_drops_the_least_recently_used_bucket_past_the_capacity() {
+        let store = MemoryStore::with_capacity(2);
+        let limit = Limit { burst: 1, per_minute: 1 };
+
+        assert!(store.take("first", &limit).unwrap().allowed);
+        assert!(store.take("second", &limit).unwrap().allowed);
+        assert!(!store.take("first", &limit).unwrap().allowed);
+        assert!(store.take("third", &limit).unwrap().allowed);
+
+        // `second` was dropped for `third`, `first` was used more recently
+        assert_eq!(store.buckets.lock().unwrap().by_key.len(), 2);
+        assert!(!store.take("first", &limit).unwrap().allowed);
+        assert!(store.take("second", &limit).unwrap().allowed);
+    }
+}
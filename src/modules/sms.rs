@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::PathBuf;
+
+use chrono::Utc;
+
+use crate::config::Config;
+
+// Implemented once per SMS gateway, `to` is an E.164 phone number
+pub trait SmsProvider {
+    fn send(&self, to: &str, body: &str) -> Result<(), String>;
+}
+
+// Development and test stand-in for a gateway, appends each message to a file per
+// recipient in `directory`, or prints it when there is no directory
+pub struct FileSmsProvider {
+    directory: Option<PathBuf>
+}
+
+impl FileSmsProvider {
+    pub fn new(directory: Option<PathBuf>) -> FileSmsProvider {
+        FileSmsProvider { directory }
+    }
+}
+
+impl SmsProvider for FileSmsProvider {
+    fn send(&self, to: &str, body: &str) -> Result<(), String> {
+        let message = format!("{} To: {}\n{}\n", Utc::now().to_rfc3339(), to, body);
+
+        match &self.directory {
+            Some(directory) => {
+                use std::io::Write;
+
+                fs::create_dir_all(directory)
+                    .map_err(|error| format!("Could not create SMS directory: {}", error))?;
+
+                let recipient: String = to
+                    .chars()
+                    .filter(|c| c.is_ascii_digit())
+                    .collect();
+
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(directory.join(format!("{}.txt", recipient)))
+                    .and_then(|mut file| file.write_all(message.as_bytes()))
+                    .map_err(|error| format!("Could not write SMS: {}", error))
+            },
+            None => {
+                println!("{}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn sms_provider_from_config(config: &Config) -> Box<dyn SmsProvider> {
+    match config.sms_provider.as_ref() {
+        "file" => Box::new(FileSmsProvider::new(Some(PathBuf::from(&config.sms_file_directory)))),
+        _ => Box::new(FileSmsProvider::new(None))
+    }
+}
+
+#[test]
+fn appends_messages_per_recipient() {
+    let directory = std::env::temp_dir().join(format!("sms-test-{}", Utc::now().timestamp_nanos()));
+    let provider = FileSmsProvider::new(Some(directory.clone()));
+
+    provider.send("+34 600 000 000", "123456 is your login code").unwrap();
+    provider.send("+34 600 000 000", "654321 is your login code").unwrap();
+
+    let contents = fs::read_to_string(directory.join("34600000000.txt")).unwrap();
+    assert!(contents.contains("123456 is your login code"));
+    assert!(contents.contains("654321 is your login code"));
+
+    fs::remove_dir_all(directory).unwrap();
+}
@@ -0,0 +1,98 @@
+use ring::hmac;
+use ring::constant_time::verify_slices_are_equal;
+use ring::rand::{SecureRandom, SystemRandom};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+// RFC 6238 defaults, which is also all that most authenticator apps support
+const DIGITS: u32 = 6;
+const PERIOD: i64 = 30;
+// Number of periods either side of the current one we accept to allow for clock drift
+const ALLOWED_SKEW: i64 = 1;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+pub fn generate_secret() -> Result<String, String> {
+    let mut secret = [0u8; 20];
+
+    SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| String::from("Could not generate TOTP secret"))?;
+
+    Ok(base32::encode(ALPHABET, &secret))
+}
+
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    let issuer = utf8_percent_encode(issuer, NON_ALPHANUMERIC).to_string();
+    let account = utf8_percent_encode(account, NON_ALPHANUMERIC).to_string();
+
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, account, secret, issuer, DIGITS, PERIOD
+    )
+}
+
+// HOTP (RFC 4226) for a given counter, TOTP just derives the counter from the clock
+fn hotp(secret: &[u8], counter: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &counter.to_be_bytes());
+    let digest = tag.as_ref();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = (u32::from(digest[offset]) & 0x7f) << 24
+        | u32::from(digest[offset + 1]) << 16
+        | u32::from(digest[offset + 2]) << 8
+        | u32::from(digest[offset + 3]);
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+// The time step the code was generated for, so the caller can refuse to accept it twice
+pub fn matching_step(secret: &str, code: &str, unix_time: i64) -> Option<i64> {
+    let secret = base32::decode(ALPHABET, secret)?;
+
+    let code = code.trim();
+    let counter = unix_time / PERIOD;
+
+    (-ALLOWED_SKEW..=ALLOWED_SKEW)
+        .map(|skew| counter + skew)
+        .filter(|step| *step >= 0)
+        .find(|step| {
+            let expected = hotp(&secret, *step as u64);
+            verify_slices_are_equal(expected.as_bytes(), code.as_bytes()).is_ok()
+        })
+}
+
+#[test]
+fn rfc_6238_test_vectors() {
+    // SHA1 vectors from RFC 6238 appendix B, truncated to 6 digits
+    let secret = b"12345678901234567890";
+    let vectors = [
+        (59, "287082"),
+        (1111111109, "081804"),
+        (1111111111, "050471"),
+        (1234567890, "005924"),
+        (2000000000, "279037"),
+    ];
+
+    for (time, code) in vectors.iter() {
+        assert_eq!(hotp(secret, (time / PERIOD) as u64), *code);
+    }
+}
+
+#[test]
+fn verification_allows_clock_skew() {
+    let secret = base32::encode(ALPHABET, b"12345678901234567890");
+
+    assert!(matching_step(&secret, "287082", 59).is_some());
+    assert!(matching_step(&secret, "287082", 59 + PERIOD).is_some());
+    assert!(matching_step(&secret, "287082", 59 + 3 * PERIOD).is_none());
+    assert!(matching_step(&secret, "000000", 59).is_none());
+}
+
+#[test]
+fn matches_the_step_the_code_was_generated_for() {
+    let secret = base32::encode(ALPHABET, b"12345678901234567890");
+
+    assert_eq!(matching_step(&secret, "287082", 59), Some(1));
+    assert_eq!(matching_step(&secret, "287082", 59 + PERIOD), Some(1));
+}
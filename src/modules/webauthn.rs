@@ -0,0 +1,458 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use ring::digest::{digest, SHA256};
+use ring::{hkdf, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use serde::Deserialize;
+use serde_cbor::Value;
+
+// Only ES256 (ECDSA P-256 with SHA-256) is supported, which every platform
+// authenticator and security key we care about can produce
+pub const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+// HKDF info for the decoy credential key, keeps it apart from anything else derived
+// from the same secret
+const DECOY_CREDENTIAL_KEY_LABEL: &[u8] = b"webauthn decoy credential id";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    pub id: String,
+    pub name: String,
+    pub display_name: String
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub alg: i64
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub credential_type: String,
+    pub id: String,
+    pub transports: Vec<String>
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String
+}
+
+// Mirrors PublicKeyCredentialCreationOptions, with binary fields base64url encoded
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingParty,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    pub timeout: u32,
+    pub attestation: String,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection
+}
+
+// Mirrors PublicKeyCredentialRequestOptions, with binary fields base64url encoded
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub timeout: u32,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    pub user_verification: String
+}
+
+#[derive(Debug, Deserialize)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    ceremony_type: String,
+    challenge: String,
+    origin: String
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RegisteredCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32
+}
+
+// What the relying party expects a ceremony to have been performed for
+pub struct Expected<'a> {
+    pub challenge: &'a str,
+    pub origin: &'a str,
+    pub rp_id: &'a str,
+    pub user_verification: bool
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    attested_credential_data: &'a [u8]
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, String> {
+    // Some client libraries pad their base64url output, the spec doesn't
+    base64::decode_config(value.trim_end_matches('='), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| String::from("Invalid base64url value"))
+}
+
+pub fn generate_challenge() -> Result<String, String> {
+    let mut challenge = [0u8; 32];
+
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| String::from("Could not generate WebAuthn challenge"))?;
+
+    Ok(encode(&challenge))
+}
+
+// Offered for names without any credentials so the options look the same whether or
+// not the account exists, the same name always gets the same ID. The key is derived
+// from `secret` rather than being it, so the IDs say nothing about the pepper
+pub fn decoy_credential_id(secret: &str, name: &str) -> Result<String, String> {
+    let key: hmac::Key = hkdf::Salt::new(hkdf::HKDF_SHA256, &[])
+        .extract(secret.as_bytes())
+        .expand(&[DECOY_CREDENTIAL_KEY_LABEL], hmac::HMAC_SHA256)
+        .map_err(|_| String::from("Could not derive the decoy credential key"))?
+        .into();
+
+    Ok(encode(hmac::sign(&key, name.to_lowercase().as_bytes()).as_ref()))
+}
+
+fn verify_client_data(client_data_json: &[u8], ceremony_type: &str, expected: &Expected) -> Result<(), String> {
+    let client_data: CollectedClientData = serde_json::from_slice(client_data_json)
+        .map_err(|_| String::from("Invalid client data"))?;
+
+    if client_data.ceremony_type != ceremony_type {
+        return Err(String::from("Unexpected ceremony type"));
+    }
+
+    if client_data.challenge != expected.challenge {
+        return Err(String::from("Challenge does not match"));
+    }
+
+    if client_data.origin != expected.origin {
+        return Err(String::from("Origin does not match"));
+    }
+
+    Ok(())
+}
+
+fn parse_authenticator_data<'a>(auth_data: &'a [u8], expected: &Expected) -> Result<AuthenticatorData<'a>, String> {
+    if auth_data.len() < 37 {
+        return Err(String::from("Authenticator data is too short"));
+    }
+
+    let parsed = AuthenticatorData {
+        rp_id_hash: &auth_data[..32],
+        flags: auth_data[32],
+        sign_count: u32::from_be_bytes(auth_data[33..37].try_into().unwrap()),
+        attested_credential_data: &auth_data[37..]
+    };
+
+    if parsed.rp_id_hash != digest(&SHA256, expected.rp_id.as_bytes()).as_ref() {
+        return Err(String::from("Relying party ID does not match"));
+    }
+
+    if parsed.flags & FLAG_USER_PRESENT == 0 {
+        return Err(String::from("User was not present"));
+    }
+
+    if expected.user_verification && parsed.flags & FLAG_USER_VERIFIED == 0 {
+        return Err(String::from("User was not verified"));
+    }
+
+    Ok(parsed)
+}
+
+fn cose_field(key: &BTreeMap<Value, Value>, label: i128) -> Option<&Value> {
+    key.get(&Value::Integer(label))
+}
+
+// Converts a COSE_Key into the uncompressed SEC1 point ring verifies against
+fn cose_to_public_key(cose_key: &[u8]) -> Result<Vec<u8>, String> {
+    let mut deserializer = serde_cbor::Deserializer::from_slice(cose_key);
+    let key = match Value::deserialize(&mut deserializer) {
+        Ok(Value::Map(key)) => key,
+        _ => return Err(String::from("Invalid credential public key"))
+    };
+
+    match (cose_field(&key, 1), cose_field(&key, 3), cose_field(&key, -1)) {
+        (Some(Value::Integer(2)), Some(Value::Integer(alg)), Some(Value::Integer(1))) if *alg == ES256 as i128 => {},
+        _ => return Err(String::from("Only ES256 credentials are supported"))
+    }
+
+    match (cose_field(&key, -2), cose_field(&key, -3)) {
+        (Some(Value::Bytes(x)), Some(Value::Bytes(y))) if x.len() == 32 && y.len() == 32 => {
+            let mut public_key = vec![0x04];
+            public_key.extend_from_slice(x);
+            public_key.extend_from_slice(y);
+            Ok(public_key)
+        },
+        _ => Err(String::from("Invalid credential public key"))
+    }
+}
+
+// Attestation statements aren't checked, we ask for "none" conveyance and
+// only need the credential public key
+pub fn verify_registration(client_data_json: &[u8], attestation_object: &[u8], expected: &Expected) -> Result<RegisteredCredential, String> {
+    verify_client_data(client_data_json, "webauthn.create", expected)?;
+
+    let auth_data = match serde_cbor::from_slice::<Value>(attestation_object) {
+        Ok(Value::Map(attestation)) => match attestation.get(&Value::Text(String::from("authData"))) {
+            Some(Value::Bytes(auth_data)) => auth_data.clone(),
+            _ => return Err(String::from("Attestation object is missing authenticator data"))
+        },
+        _ => return Err(String::from("Invalid attestation object"))
+    };
+
+    let parsed = parse_authenticator_data(&auth_data, expected)?;
+
+    if parsed.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(String::from("Authenticator data is missing the credential"));
+    }
+
+    // aaguid (16 bytes), credential ID length (2 bytes), credential ID, COSE key
+    let credential_data = parsed.attested_credential_data;
+
+    if credential_data.len() < 18 {
+        return Err(String::from("Authenticator data is too short"));
+    }
+
+    let credential_id_length = u16::from_be_bytes([credential_data[16], credential_data[17]]) as usize;
+
+    if credential_data.len() < 18 + credential_id_length {
+        return Err(String::from("Authenticator data is too short"));
+    }
+
+    Ok(RegisteredCredential {
+        credential_id: credential_data[18..18 + credential_id_length].to_vec(),
+        public_key: cose_to_public_key(&credential_data[18 + credential_id_length..])?,
+        sign_count: parsed.sign_count
+    })
+}
+
+// Returns the authenticator's new signature counter so it can be stored
+pub fn verify_authentication(
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+    expected: &Expected
+) -> Result<u32, String> {
+    verify_client_data(client_data_json, "webauthn.get", expected)?;
+
+    let parsed = parse_authenticator_data(authenticator_data, expected)?;
+
+    let mut signed_data = authenticator_data.to_vec();
+    signed_data.extend_from_slice(digest(&SHA256, client_data_json).as_ref());
+
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, public_key)
+        .verify(&signed_data, signature)
+        .map_err(|_| String::from("Invalid signature"))?;
+
+    // Authenticators that don't keep a counter always report 0, otherwise a counter
+    // that didn't move forward means the credential has probably been cloned
+    if (parsed.sign_count != 0 || stored_sign_count != 0) && parsed.sign_count <= stored_sign_count {
+        return Err(String::from("Signature counter did not increase"));
+    }
+
+    Ok(parsed.sign_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    const RP_ID: &str = "localhost";
+    const ORIGIN: &str = "http://localhost:3000";
+
+    // Behaves like a security key: a single ES256 credential and a signature counter
+    struct SoftwareAuthenticator {
+        key_pair: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        sign_count: u32
+    }
+
+    impl SoftwareAuthenticator {
+        fn new() -> SoftwareAuthenticator {
+            let rng = SystemRandom::new();
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+
+            SoftwareAuthenticator {
+                key_pair: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap(),
+                credential_id: vec![7; 16],
+                sign_count: 0
+            }
+        }
+
+        fn client_data(ceremony_type: &str, challenge: &str) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::json!({
+                "type": ceremony_type,
+                "challenge": challenge,
+                "origin": ORIGIN
+            })).unwrap()
+        }
+
+        fn authenticator_data(&mut self, flags: u8, attested_credential_data: &[u8]) -> Vec<u8> {
+            self.sign_count += 1;
+
+            let mut auth_data = digest(&SHA256, RP_ID.as_bytes()).as_ref().to_vec();
+            auth_data.push(flags);
+            auth_data.extend_from_slice(&self.sign_count.to_be_bytes());
+            auth_data.extend_from_slice(attested_credential_data);
+            auth_data
+        }
+
+        fn create(&mut self, challenge: &str) -> (Vec<u8>, Vec<u8>) {
+            let public_key = self.key_pair.public_key().as_ref();
+
+            let mut cose_key = BTreeMap::new();
+            cose_key.insert(Value::Integer(1), Value::Integer(2));
+            cose_key.insert(Value::Integer(3), Value::Integer(ES256 as i128));
+            cose_key.insert(Value::Integer(-1), Value::Integer(1));
+            cose_key.insert(Value::Integer(-2), Value::Bytes(public_key[1..33].to_vec()));
+            cose_key.insert(Value::Integer(-3), Value::Bytes(public_key[33..65].to_vec()));
+
+            let mut credential_data = vec![0; 16];
+            credential_data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            credential_data.extend_from_slice(&self.credential_id);
+            credential_data.extend_from_slice(&serde_cbor::to_vec(&Value::Map(cose_key)).unwrap());
+
+            let auth_data = self.authenticator_data(
+                FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_CREDENTIAL_DATA,
+                &credential_data
+            );
+
+            let mut attestation = BTreeMap::new();
+            attestation.insert(Value::Text(String::from("fmt")), Value::Text(String::from("none")));
+            attestation.insert(Value::Text(String::from("attStmt")), Value::Map(BTreeMap::new()));
+            attestation.insert(Value::Text(String::from("authData")), Value::Bytes(auth_data));
+
+            (
+                SoftwareAuthenticator::client_data("webauthn.create", challenge),
+                serde_cbor::to_vec(&Value::Map(attestation)).unwrap()
+            )
+        }
+
+        fn get(&mut self, challenge: &str, flags: u8) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+            let client_data_json = SoftwareAuthenticator::client_data("webauthn.get", challenge);
+            let auth_data = self.authenticator_data(flags, &[]);
+
+            let mut signed_data = auth_data.clone();
+            signed_data.extend_from_slice(digest(&SHA256, &client_data_json).as_ref());
+
+            let signature = self.key_pair.sign(&SystemRandom::new(), &signed_data).unwrap();
+
+            (client_data_json, auth_data, signature.as_ref().to_vec())
+        }
+    }
+
+    fn expected(challenge: &str) -> Expected<'_> {
+        Expected { challenge, origin: ORIGIN, rp_id: RP_ID, user_verification: true }
+    }
+
+    #[test]
+    fn registration_and_authentication() {
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, attestation_object) = authenticator.create(&challenge);
+        let credential = verify_registration(&client_data_json, &attestation_object, &expected(&challenge)).unwrap();
+
+        assert_eq!(credential.credential_id, authenticator.credential_id);
+        assert_eq!(credential.public_key, authenticator.key_pair.public_key().as_ref().to_vec());
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, auth_data, signature) = authenticator.get(&challenge, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        let sign_count = verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, credential.sign_count, &expected(&challenge)
+        ).unwrap();
+
+        assert_eq!(sign_count, 2);
+    }
+
+    #[test]
+    fn authentication_rejects_wrong_challenge_and_replays() {
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, attestation_object) = authenticator.create(&challenge);
+        let credential = verify_registration(&client_data_json, &attestation_object, &expected(&challenge)).unwrap();
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, auth_data, signature) = authenticator.get(&challenge, FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+
+        let other_challenge = generate_challenge().unwrap();
+        assert!(verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, credential.sign_count, &expected(&other_challenge)
+        ).is_err());
+
+        let sign_count = verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, credential.sign_count, &expected(&challenge)
+        ).unwrap();
+
+        assert!(verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, sign_count, &expected(&challenge)
+        ).is_err());
+    }
+
+    #[test]
+    fn authentication_requires_user_verification_when_asked() {
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, attestation_object) = authenticator.create(&challenge);
+        let credential = verify_registration(&client_data_json, &attestation_object, &expected(&challenge)).unwrap();
+
+        let challenge = generate_challenge().unwrap();
+        let (client_data_json, auth_data, signature) = authenticator.get(&challenge, FLAG_USER_PRESENT);
+
+        assert!(verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, credential.sign_count, &expected(&challenge)
+        ).is_err());
+
+        let presence_only = Expected { user_verification: false, ..expected(&challenge) };
+        assert!(verify_authentication(
+            &client_data_json, &auth_data, &signature, &credential.public_key, credential.sign_count, &presence_only
+        ).is_ok());
+    }
+
+    #[test]
+    fn decoy_credentials_are_stable_per_name() {
+        assert_eq!(decoy_credential_id("secret", "nobody"), decoy_credential_id("secret", "Nobody"));
+        assert_ne!(decoy_credential_id("secret", "nobody"), decoy_credential_id("secret", "somebody"));
+        assert_ne!(decoy_credential_id("secret", "nobody"), decoy_credential_id("other secret", "nobody"));
+
+        // Not a plain HMAC keyed with the secret
+        let pepper_key = hmac::Key::new(hmac::HMAC_SHA256, b"secret");
+        let pepper_id = encode(hmac::sign(&pepper_key, b"nobody").as_ref());
+        assert_ne!(decoy_credential_id("secret", "nobody").unwrap(), pepper_id);
+    }
+}
@@ -0,0 +1,7 @@
+use actix_web::{ Scope, web };
+use crate::handlers::admin::unlock_user;
+
+pub fn admin_routes() -> Scope {
+    web::scope("/admin")
+        .route("/users/{id}/unlock", web::post().to(unlock_user))
+}
@@ -1,7 +1,67 @@
 use actix_web::{Scope, web};
-use crate::handlers::user::{login_user};
+use crate::middleware::rate_limit::{AccountKey, RateLimit};
+use crate::handlers::user::{login_user, verify_mfa_login};
+use crate::handlers::webauthn::{authentication_options, authenticate};
+use crate::handlers::email_verification::{verify_email, resend_verification_email};
+use crate::handlers::password::{forgot_password, reset_password, change_expired_password};
+use crate::handlers::magic_link::{send_magic_link, verify_magic_link};
+use crate::handlers::login_code::{send_login_code, verify_login_code};
 
-pub fn login() -> Scope {
-    web::scope("/app")
-        .route("/login", web::post().to(login_user))
-}
\ No newline at end of file
+// The routes past password logins need flows only `PgRepository` implements, so they're
+// only mounted `with_postgres`
+pub fn login(with_postgres: bool) -> Scope {
+    let scope = web::scope("/app")
+        .service(
+            web::resource("/login")
+                .wrap(RateLimit::from_config("login").per_account(AccountKey::BodyField("name")))
+                .route(web::post().to(login_user))
+        );
+
+    if !with_postgres {
+        return scope;
+    }
+
+    scope
+        .service(
+            web::resource("/login/mfa")
+                .wrap(RateLimit::from_config("login_mfa"))
+                .route(web::post().to(verify_mfa_login))
+        )
+        .service(
+            web::resource("/login/webauthn/options")
+                .wrap(RateLimit::from_config("login_webauthn_options"))
+                .route(web::post().to(authentication_options))
+        )
+        .service(
+            web::resource("/login/webauthn")
+                .wrap(RateLimit::from_config("login_webauthn"))
+                .route(web::post().to(authenticate))
+        )
+        // Each of these sends an email or a text, so the account bucket also stops
+        // anyone flooding a user's inbox
+        .service(
+            web::resource("/login/magic-link")
+                .wrap(RateLimit::from_config("magic_link").per_account(AccountKey::BodyField("email")))
+                .route(web::post().to(send_magic_link))
+        )
+        .route("/login/magic-link/verify", web::post().to(verify_magic_link))
+        .service(
+            web::resource("/login/code")
+                .wrap(RateLimit::from_config("login_code").per_account(AccountKey::BodyField("email")))
+                .route(web::post().to(send_login_code))
+        )
+        .route("/login/code/verify", web::post().to(verify_login_code))
+        .route("/verify-email", web::post().to(verify_email))
+        .service(
+            web::resource("/verify-email/resend")
+                .wrap(RateLimit::from_config("verify_email_resend").per_account(AccountKey::BodyField("email")))
+                .route(web::post().to(resend_verification_email))
+        )
+        .service(
+            web::resource("/password/forgot")
+                .wrap(RateLimit::from_config("password_forgot").per_account(AccountKey::BodyField("email")))
+                .route(web::post().to(forgot_password))
+        )
+        .route("/password/reset", web::post().to(reset_password))
+        .route("/password/expired", web::post().to(change_expired_password))
+}
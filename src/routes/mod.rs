@@ -1,3 +1,4 @@
 pub mod user;
 pub mod login;
-pub mod session;
\ No newline at end of file
+pub mod session;
+pub mod admin;
\ No newline at end of file
@@ -1,8 +1,13 @@
 use actix_web::{ Scope, web };
 use crate::handlers::user::{logout_user, reauth_user};
+use crate::middleware::rate_limit::{AccountKey, RateLimit};
 
 pub fn session() -> Scope {
     web::scope("/session")
         .route("/logout", web::post().to(logout_user))
-        .route("/refresh", web::post().to(reauth_user))
+        .service(
+            web::resource("/refresh")
+                .wrap(RateLimit::from_config("refresh").per_account(AccountKey::RefreshToken))
+                .route(web::post().to(reauth_user))
+        )
 }
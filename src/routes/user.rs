@@ -1,10 +1,62 @@
 use actix_web::{ Scope, web };
 use crate::handlers::user::*;
+use crate::handlers::mfa::{start_totp_enrolment, confirm_totp_enrolment, regenerate_recovery_codes};
+use crate::handlers::webauthn::{registration_options, register};
+use crate::handlers::password::change_password;
+use crate::handlers::login_code::{send_phone_verification_code, verify_phone};
+use crate::middleware::rate_limit::{AccountKey, RateLimit};
+use crate::middleware::step_up::StepUp;
+use crate::modules::jwt::Amr;
 
-pub fn user_routes() -> Scope {
-    web::scope("/users")
+// Second factors and password changes need flows only `PgRepository` implements, so
+// they're only mounted `with_postgres`
+pub fn user_routes(with_postgres: bool) -> Scope {
+    let scope = web::scope("/users")
         .route("/all", web::get().to(get_users))
-        .route("/create", web::post().to(create_user))
-        .route("/{id}", web::get().to(get_user)
-    )
-}
\ No newline at end of file
+        .service(
+            web::resource("/create")
+                .wrap(RateLimit::from_config("create").per_account(AccountKey::BodyField("email")))
+                .route(web::post().to(create_user))
+        );
+
+    let scope = if with_postgres {
+        scope
+            .service(
+                web::resource("/me/mfa/totp")
+                    .wrap(StepUp::from_config())
+                    .route(web::post().to(start_totp_enrolment))
+            )
+            .route("/me/mfa/totp/confirm", web::post().to(confirm_totp_enrolment))
+            .service(
+                web::resource("/me/mfa/recovery-codes")
+                    .wrap(StepUp::from_config())
+                    .route(web::post().to(regenerate_recovery_codes))
+            )
+            .service(
+                web::resource("/me/webauthn/register/options")
+                    .wrap(StepUp::from_config())
+                    .route(web::post().to(registration_options))
+            )
+            .route("/me/webauthn/register", web::post().to(register))
+            // Each request sends a text
+            .service(
+                web::resource("/me/phone/code")
+                    .wrap(RateLimit::from_config("phone_verification").per_account(AccountKey::Authenticated))
+                    .route(web::post().to(send_phone_verification_code))
+            )
+            .route("/me/phone/verify", web::post().to(verify_phone))
+            // Takes the current password, so guessing it is limited like logging in. Only
+            // sessions that started with the password can replace it
+            .service(
+                web::resource("/me/password")
+                    .wrap(StepUp::from_config().requiring(Amr::Pwd))
+                    .wrap(RateLimit::from_config("change_password").per_account(AccountKey::Authenticated))
+                    .route(web::post().to(change_password))
+            )
+    } else {
+        scope
+    };
+
+    // Last, so it doesn't swallow the routes above
+    scope.route("/{id}", web::get().to(get_user))
+}
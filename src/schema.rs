@@ -1,3 +1,64 @@
+table! {
+    login_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        channel -> Varchar,
+        code_hash -> Varchar,
+        attempts -> Int4,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    mfa_recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    password_history (id) {
+        id -> Int4,
+        user_id -> Int4,
+        password_hash -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    rate_limit_buckets (bucket_key) {
+        bucket_key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    signing_keys (id) {
+        id -> Int4,
+        kid -> Varchar,
+        secret -> Varchar,
+        created_at -> Timestamp,
+        retired_at -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    user_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        purpose -> Varchar,
+        token_hash -> Varchar,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+        created_at -> Timestamp,
+    }
+}
+
 table! {
     users (id) {
         id -> Int4,
@@ -5,5 +66,53 @@ table! {
         email -> Varchar,
         password -> Varchar,
         refresh_token -> Nullable<Varchar>,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        email_verified -> Bool,
+        locale -> Varchar,
+        phone -> Nullable<Varchar>,
+        failed_login_attempts -> Int4,
+        locked_until -> Nullable<Timestamp>,
+        is_admin -> Bool,
+        password_changed_at -> Timestamp,
+        totp_last_step -> Nullable<Int8>,
+        phone_verified -> Bool,
+    }
+}
+
+table! {
+    webauthn_challenges (challenge_hash) {
+        challenge_hash -> Varchar,
+        expires_at -> Timestamp,
+    }
+}
+
+table! {
+    webauthn_credentials (id) {
+        id -> Int4,
+        user_id -> Int4,
+        credential_id -> Varchar,
+        public_key -> Bytea,
+        sign_count -> Int8,
+        transports -> Array<Text>,
+        created_at -> Timestamp,
     }
 }
+
+joinable!(login_codes -> users (user_id));
+joinable!(mfa_recovery_codes -> users (user_id));
+joinable!(password_history -> users (user_id));
+joinable!(user_tokens -> users (user_id));
+joinable!(webauthn_credentials -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    login_codes,
+    mfa_recovery_codes,
+    password_history,
+    rate_limit_buckets,
+    signing_keys,
+    user_tokens,
+    users,
+    webauthn_challenges,
+    webauthn_credentials,
+);
@@ -4,14 +4,19 @@ mod tests {
     #[actix_rt::test]
     async fn login() {
         use actix_web::{App, test, http::header};
-        use crate::db::db_connection::establish_connection;
+        use crate::db::memory_repository::MemoryRepository;
         use crate::routes::login::login;
         use crate::handlers::user::{UserLoginResponse};
 
+        let (users, sessions) = MemoryRepository::new()
+            .with_user("miguel", "miguel@email.com", "123")
+            .into_repositories();
+
         let mut app = test::init_service(
             App::new()
-                .data(establish_connection())
-                .service(login())
+                .data(users)
+                .data(sessions)
+                .service(login(false))
         ).await;
 
         let payload = r#"{"name": "miguel", "password": "123"}"#.as_bytes();
@@ -27,4 +32,109 @@ mod tests {
         assert_eq!(response.user_logged_in.name, "miguel");
         assert_eq!(response.user_logged_in.email, "miguel@email.com");
     }
+
+    #[actix_rt::test]
+    async fn wrong_password_is_unauthorized() {
+        use actix_web::{App, test, http::{header, StatusCode}};
+        use crate::db::memory_repository::MemoryRepository;
+        use crate::errors::errors::{Problem, PROBLEM_JSON};
+        use crate::routes::login::login;
+
+        let (users, sessions) = MemoryRepository::new()
+            .with_user("miguel", "miguel@email.com", "123")
+            .into_repositories();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(users)
+                .data(sessions)
+                .service(login(false))
+        ).await;
+
+        let payload = r#"{"name": "miguel", "password": "1234"}"#.as_bytes();
+
+        let req = test::TestRequest::post()
+            .uri("/app/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(payload)
+            .to_request();
+
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), PROBLEM_JSON);
+
+        let problem: Problem = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(problem.status, 401);
+        assert_eq!(problem.code, "invalid_credentials");
+        assert_eq!(problem.detail, "Invalid credentials");
+    }
+
+    #[actix_rt::test]
+    async fn repeated_wrong_passwords_lock_the_account() {
+        use actix_web::{App, test, http::{header, StatusCode}};
+        use crate::db::memory_repository::MemoryRepository;
+        use crate::errors::errors::Problem;
+        use crate::routes::login::login;
+
+        // Its own name, so the per account rate limit isn't shared with the other tests
+        let (users, sessions) = MemoryRepository::new()
+            .with_user("dana", "dana@email.com", "123")
+            .into_repositories();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(users)
+                .data(sessions)
+                .service(login(false))
+        ).await;
+
+        let wrong_password = || test::TestRequest::post()
+            .uri("/app/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(r#"{"name": "dana", "password": "1234"}"#.as_bytes())
+            .to_request();
+
+        // One short of the default `lockout_threshold`
+        for _ in 0..4 {
+            let response = test::call_service(&mut app, wrong_password()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let response = test::call_service(&mut app, wrong_password()).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let problem: Problem = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(problem.code, "account_locked");
+    }
+
+    #[actix_rt::test]
+    async fn malformed_body_is_a_bad_request() {
+        use actix_web::{App, test, http::{header, StatusCode}};
+        use crate::db::memory_repository::MemoryRepository;
+        use crate::errors::errors::{json_config, Problem};
+        use crate::routes::login::login;
+
+        let (users, sessions) = MemoryRepository::new().into_repositories();
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(json_config())
+                .data(users)
+                .data(sessions)
+                .service(login(false))
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/app/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(r#"{"name": "miguel"}"#.as_bytes())
+            .to_request();
+
+        let response = test::call_service(&mut app, req).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let problem: Problem = serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(problem.code, "invalid_body");
+    }
 }
\ No newline at end of file
@@ -1,19 +1,22 @@
 #[cfg(test)] 
 mod tests {
     use actix_web::{App, test, http::header};
-    use crate::db::db_connection::establish_connection;
+    use crate::db::memory_repository::MemoryRepository;
     use crate::routes::user::user_routes;
     use crate::handlers::user::{CreateUserResponse};
 
     #[actix_rt::test]
     async fn create() {
+        let (users, sessions) = MemoryRepository::new().into_repositories();
+
         let mut app = test::init_service(
             App::new()
-                .data(establish_connection())
-                .service(user_routes())
+                .data(users)
+                .data(sessions)
+                .service(user_routes(false))
         ).await;
 
-        let payload = r#"{"name": "alex z", "email": "alexz@email.com", "password": "123" }"#.as_bytes();
+        let payload = r#"{"name": "alex z", "email": "alexz@email.com", "password": "vX9#qLp2!mZr7wTe" }"#.as_bytes();
 
         let request = test::TestRequest::post()
             .uri("/users/create")
@@ -27,4 +30,63 @@ mod tests {
         assert_eq!(response.email, "alexz@email.com");
         
     }
-}
\ No newline at end of file
+
+    #[actix_rt::test]
+    async fn create_with_taken_email_conflicts() {
+        use actix_web::http::StatusCode;
+
+        let (users, sessions) = MemoryRepository::new()
+            .with_user("alex", "alexz@email.com", "123")
+            .into_repositories();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(users)
+                .data(sessions)
+                .service(user_routes(false))
+        ).await;
+
+        let payload = r#"{"name": "alex z", "email": "alexz@email.com", "password": "vX9#qLp2!mZr7wTe" }"#.as_bytes();
+
+        let request = test::TestRequest::post()
+            .uri("/users/create")
+            .header(header::CONTENT_TYPE, "application/json")
+            .set_payload(payload)
+            .to_request();
+
+        let response = test::call_service(&mut app, request).await;
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_rt::test]
+    async fn create_with_taken_phone_conflicts() {
+        use actix_web::http::StatusCode;
+
+        let (users, sessions) = MemoryRepository::new().into_repositories();
+
+        let mut app = test::init_service(
+            App::new()
+                .data(users)
+                .data(sessions)
+                .service(user_routes(false))
+        ).await;
+
+        let payloads = [
+            (r#"{"name": "alex", "email": "alex@email.com", "password": "vX9#qLp2!mZr7wTe", "phone": "+34600000000" }"#, StatusCode::OK),
+            (r#"{"name": "alex z", "email": "alexz@email.com", "password": "vX9#qLp2!mZr7wTe", "phone": "+34600000000" }"#, StatusCode::CONFLICT)
+        ];
+
+        for (payload, status) in payloads.iter() {
+            let request = test::TestRequest::post()
+                .uri("/users/create")
+                .header(header::CONTENT_TYPE, "application/json")
+                .set_payload(payload.as_bytes())
+                .to_request();
+
+            let response = test::call_service(&mut app, request).await;
+
+            assert_eq!(response.status(), *status);
+        }
+    }
+}